@@ -0,0 +1,96 @@
+//! An async wrapper over `Store`, for servers that can't block in their handlers. Each call runs
+//! the matching `Store` method on tokio's blocking thread pool, so there's no logic here of its
+//! own. Only built with the `asynced` feature.
+
+use crate::store::{Error, ItemID, Store, Tag, TagID};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream};
+use std::sync::Arc;
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Entries a stream reads ahead of its consumer before the blocking side waits
+const STREAM_BUFFER: usize = 64;
+
+/// A `Store` shared between tasks. Cloning it is cheap, and every clone uses the same store.
+#[derive(Clone)]
+pub struct AsyncStore {
+    store: Arc<Store>,
+}
+
+impl AsyncStore {
+    pub fn new(store: Store) -> AsyncStore {
+        AsyncStore {
+            store: Arc::new(store),
+        }
+    }
+
+    /// The wrapped store, for methods that aren't wrapped here. Calls on it block.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub async fn tag_string(&self, item: ItemID, tag_name: &str) -> Result<()> {
+        let tag_name = tag_name.to_string();
+        self.run(move |store| store.tag_string(item, &tag_name))
+            .await
+    }
+
+    pub async fn untag_string(&self, item: ItemID, tag_name: &str) -> Result<bool> {
+        let tag_name = tag_name.to_string();
+        self.run(move |store| store.untag_string(item, &tag_name))
+            .await
+    }
+
+    pub async fn get_tag_id(&self, tag_name: &str) -> Result<Option<TagID>> {
+        let tag_name = tag_name.to_string();
+        self.run(move |store| store.get_tag_id(&tag_name)).await
+    }
+
+    pub async fn tag_item_count(&self, tag: TagID) -> Result<u64> {
+        self.run(move |store| store.tag_item_count(tag)).await
+    }
+
+    /// Items tagged `id`, read on the blocking pool a few at a time as the stream is polled.
+    pub fn get_tag_item_ids(&self, id: TagID) -> impl Stream<Item = Result<ItemID>> {
+        self.stream(move |store| store.get_tag_item_ids_owned(id))
+    }
+
+    /// Tags on the item `id`, like `get_tag_item_ids`. This needs the item -> tags index.
+    pub fn get_item_tags(&self, id: ItemID) -> impl Stream<Item = Result<Tag>> {
+        self.stream(move |store| store.get_item_tags_owned(id))
+    }
+
+    /// Run `f` on the blocking pool, for calls that aren't wrapped here. A panic in `f` comes
+    /// back as `Error::InternalError`.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Store) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(|_| Error::InternalError {})?
+    }
+
+    /// Drain the iterator `f` makes on the blocking pool, sending each entry to the returned
+    /// stream. Dropping the stream stops the iteration.
+    fn stream<T, I, F>(&self, f: F) -> impl Stream<Item = Result<T>>
+    where
+        F: FnOnce(&Store) -> I + Send + 'static,
+        I: Iterator<Item = Result<T>>,
+        T: Send + 'static,
+    {
+        let (mut sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || {
+            for el in f(&store) {
+                if futures::executor::block_on(sender.send(el)).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+}
@@ -0,0 +1,158 @@
+use crate::store::{Error, ItemID, Store, Tag, TagID};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Differences between a base store and another store. "Added" means present in the other store
+/// but not the base one; "removed" is the reverse.
+///
+/// Tag IDs are local to each store, so tags and associations are compared by tag name.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    pub tags_added: Vec<String>,
+    pub tags_removed: Vec<String>,
+
+    pub items_added: Vec<ItemID>,
+    pub items_removed: Vec<ItemID>,
+
+    pub associations_added: Vec<(ItemID, String)>,
+    pub associations_removed: Vec<(ItemID, String)>,
+}
+
+impl StoreDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == StoreDiff::default()
+    }
+}
+
+/// Compare two stores by walking both association streams in item order.
+pub fn diff(base: &Store, other: &Store) -> Result<StoreDiff> {
+    let base_names = tag_names(base)?;
+    let other_names = tag_names(other)?;
+
+    let mut diff = StoreDiff::default();
+
+    let base_vocab: BTreeSet<&String> = base_names.values().collect();
+    let other_vocab: BTreeSet<&String> = other_names.values().collect();
+    diff.tags_added = other_vocab
+        .difference(&base_vocab)
+        .map(|name| name.to_string())
+        .collect();
+    diff.tags_removed = base_vocab
+        .difference(&other_vocab)
+        .map(|name| name.to_string())
+        .collect();
+
+    let mut base_items = group_by_item(base.all_associations());
+    let mut other_items = group_by_item(other.all_associations());
+
+    let mut base_next = base_items.next().transpose()?;
+    let mut other_next = other_items.next().transpose()?;
+    loop {
+        match (base_next.take(), other_next.take()) {
+            (None, None) => break,
+            (Some((item, tags)), None) => {
+                diff.items_removed.push(item);
+                record(&mut diff.associations_removed, item, &base_names, &tags);
+                base_next = base_items.next().transpose()?;
+            }
+            (None, Some((item, tags))) => {
+                diff.items_added.push(item);
+                record(&mut diff.associations_added, item, &other_names, &tags);
+                other_next = other_items.next().transpose()?;
+            }
+            (Some((base_item, base_tags)), Some((other_item, other_tags))) => {
+                match base_item.cmp(&other_item) {
+                    Ordering::Less => {
+                        diff.items_removed.push(base_item);
+                        record(
+                            &mut diff.associations_removed,
+                            base_item,
+                            &base_names,
+                            &base_tags,
+                        );
+                        base_next = base_items.next().transpose()?;
+                        other_next = Some((other_item, other_tags));
+                    }
+                    Ordering::Greater => {
+                        diff.items_added.push(other_item);
+                        record(
+                            &mut diff.associations_added,
+                            other_item,
+                            &other_names,
+                            &other_tags,
+                        );
+                        base_next = Some((base_item, base_tags));
+                        other_next = other_items.next().transpose()?;
+                    }
+                    Ordering::Equal => {
+                        let base_set = named(&base_names, &base_tags);
+                        let other_set = named(&other_names, &other_tags);
+                        for name in other_set.difference(&base_set) {
+                            diff.associations_added.push((other_item, name.to_string()));
+                        }
+                        for name in base_set.difference(&other_set) {
+                            diff.associations_removed
+                                .push((base_item, name.to_string()));
+                        }
+                        base_next = base_items.next().transpose()?;
+                        other_next = other_items.next().transpose()?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+fn tag_names(store: &Store) -> Result<HashMap<TagID, String>> {
+    store
+        .all_tags()
+        .map(|tag| tag.map(|Tag { id, name, .. }| (id, name)))
+        .collect()
+}
+
+/// The names of `tags`. Tags with no name (dangling index entries) are skipped.
+fn named<'a>(names: &'a HashMap<TagID, String>, tags: &[TagID]) -> BTreeSet<&'a String> {
+    tags.iter().filter_map(|tag| names.get(tag)).collect()
+}
+
+fn record(
+    dest: &mut Vec<(ItemID, String)>,
+    item: ItemID,
+    names: &HashMap<TagID, String>,
+    tags: &[TagID],
+) {
+    for name in named(names, tags) {
+        dest.push((item, name.to_string()));
+    }
+}
+
+/// Group a stream of associations, ordered by item, into each item's tags.
+fn group_by_item<I>(associations: I) -> impl Iterator<Item = Result<(ItemID, Vec<TagID>)>>
+where
+    I: Iterator<Item = Result<(ItemID, TagID)>>,
+{
+    let mut associations = associations.peekable();
+
+    std::iter::from_fn(move || {
+        let (item, tag) = match associations.next()? {
+            Ok(pair) => pair,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let mut tags = vec![tag];
+        loop {
+            let next_tag = match associations.peek() {
+                Some(Ok((next_item, next_tag))) if *next_item == item => *next_tag,
+                _ => break,
+            };
+            tags.push(next_tag);
+            associations.next();
+        }
+
+        Some(Ok((item, tags)))
+    })
+}
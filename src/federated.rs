@@ -0,0 +1,70 @@
+use crate::store::{Error, ItemID, Store, Tag, TagID};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An item in a `FederatedStore`, namespaced by the index of the store it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FederatedItemID {
+    pub store: usize,
+    pub item: ItemID,
+}
+
+impl std::fmt::Display for FederatedItemID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.store, self.item)
+    }
+}
+
+/// Read-only view over several stores at once.
+///
+/// Tag IDs are local to each store, so tags are addressed by name and resolved in every store.
+pub struct FederatedStore {
+    stores: Vec<Store>,
+}
+
+impl FederatedStore {
+    pub fn new(stores: Vec<Store>) -> FederatedStore {
+        FederatedStore { stores }
+    }
+
+    pub fn stores(&self) -> &[Store] {
+        &self.stores
+    }
+
+    /// Resolve a tag name in each store, by store index.
+    pub fn get_tag_ids(&self, tag_name: &str) -> Result<Vec<Option<TagID>>> {
+        self.stores
+            .iter()
+            .map(|store| store.get_tag_id(tag_name))
+            .collect()
+    }
+
+    /// All items tagged with `tag_name` in any store, ordered by store index.
+    pub fn get_tag_item_ids<'a>(
+        &'a self,
+        tag_name: &'a str,
+    ) -> impl Iterator<Item = Result<FederatedItemID>> + 'a {
+        self.stores.iter().enumerate().flat_map(
+            move |(index, store)| -> Box<dyn Iterator<Item = Result<FederatedItemID>> + 'a> {
+                match store.get_tag_id(tag_name) {
+                    Ok(Some(tag)) => {
+                        Box::new(store.get_tag_item_ids(tag).map(move |item| {
+                            item.map(|item| FederatedItemID { store: index, item })
+                        }))
+                    }
+                    Ok(None) => Box::new(std::iter::empty()),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                }
+            },
+        )
+    }
+
+    pub fn get_item_tags(&self, id: FederatedItemID) -> impl Iterator<Item = Result<Tag>> + '_ {
+        let tags: Box<dyn Iterator<Item = Result<Tag>> + '_> = match self.stores.get(id.store) {
+            Some(store) => Box::new(store.get_item_tags(id.item)),
+            None => Box::new(std::iter::once(Err(Error::ItemNotFound { id: id.item }))),
+        };
+
+        tags
+    }
+}
@@ -1,15 +1,41 @@
+/// Bytes in an encoded ID: a big-endian u64. Compound keys are two of these back to back.
+pub const ID_LEN: usize = 8;
+
 pub trait Id:
-    From<u64> + Into<u64> + Into<[u8; 8]> + From<[u8; 8]> + std::fmt::Display + Into<sled::IVec>
+    From<u64>
+    + Into<u64>
+    + Into<[u8; ID_LEN]>
+    + From<[u8; ID_LEN]>
+    + std::fmt::Display
+    + Into<sled::IVec>
+    + Copy
 {
+    /// The ID as 16 zero-padded hex digits, the same as its bytes in a compound key.
+    fn to_hex(&self) -> String {
+        let id: u64 = (*self).into();
+        format!("{:016x}", id)
+    }
 }
 
 macro_rules! generate_id {
     ($id:ident) => {
-        #[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        #[derive(
+            Debug,
+            serde::Serialize,
+            serde::Deserialize,
+            Clone,
+            Copy,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Hash,
+        )]
+        #[serde(transparent)]
         pub struct $id(u64);
 
         impl $id {
-            fn to_bytes(&self) -> [u8; 8] {
+            fn to_bytes(&self) -> [u8; crate::id::ID_LEN] {
                 self.0.to_be_bytes()
             }
         }
@@ -22,6 +48,16 @@ macro_rules! generate_id {
             }
         }
 
+        /// Parse a decimal ID. Anything that isn't a `u64`, including values past `u64::MAX`,
+        /// is an error rather than being truncated.
+        impl std::str::FromStr for $id {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(text: &str) -> Result<$id, Self::Err> {
+                Ok($id(text.parse()?))
+            }
+        }
+
         impl From<u64> for $id {
             fn from(id: u64) -> $id {
                 $id(id)
@@ -34,7 +70,7 @@ macro_rules! generate_id {
             fn try_from(value: &[u8]) -> Result<$id, Self::Error> {
                 use std::convert::TryInto;
 
-                let bytes: [u8; 8] = value.try_into()?;
+                let bytes: [u8; crate::id::ID_LEN] = value.try_into()?;
                 Ok(bytes.into())
             }
         }
@@ -55,15 +91,15 @@ macro_rules! generate_id {
         }
 
         /// Convert the ID to a big-endian slice of u8
-        impl Into<[u8; 8]> for $id {
-            fn into(self) -> [u8; 8] {
+        impl Into<[u8; crate::id::ID_LEN]> for $id {
+            fn into(self) -> [u8; crate::id::ID_LEN] {
                 self.to_bytes()
             }
         }
 
         /// Convert 8 big-endian bytes into the ID
-        impl From<[u8; 8]> for $id {
-            fn from(bytes: [u8; 8]) -> $id {
+        impl From<[u8; crate::id::ID_LEN]> for $id {
+            fn from(bytes: [u8; crate::id::ID_LEN]) -> $id {
                 u64::from_be_bytes(bytes).into()
             }
         }
@@ -71,7 +107,7 @@ macro_rules! generate_id {
         /// Convert to a Sled IVec, to use in the KV index.
         impl Into<sled::IVec> for $id {
             fn into(self) -> sled::IVec {
-                let bytes: [u8; 8] = self.into();
+                let bytes: [u8; crate::id::ID_LEN] = self.into();
                 (&bytes).into()
             }
         }
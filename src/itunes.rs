@@ -1,20 +1,304 @@
 use chrono::{DateTime, Utc};
+use plist::stream::Event;
 use serde::{Deserialize, Serialize};
+use serde_path_to_error::Segment;
+use snafu::Snafu;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("library file \"{}\" not found", path.display()))]
+    FileNotFound { path: PathBuf },
+
+    #[snafu(display("couldn't read \"{}\": {}", path.display(), source))]
+    ReadFailed { path: PathBuf, source: io::Error },
+
+    #[snafu(display("plist parse error at {}: {}", field, message))]
+    PlistParse { field: String, message: String },
+
+    #[snafu(display("couldn't parse date at {}: {}", field, message))]
+    DateParse { field: String, message: String },
+
+    #[snafu(display("unexpected library schema at {}: {}", field, message))]
+    UnexpectedSchema { field: String, message: String },
+}
+
+impl Error {
+    /// Guidance for the user on how to fix a bad export.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Error::FileNotFound { .. } => {
+                "export the library from iTunes with File > Library > Export Library..."
+            }
+            Error::ReadFailed { .. } => "check the library file's permissions",
+            Error::PlistParse { .. } => {
+                "the file isn't a valid plist; try re-exporting it from iTunes"
+            }
+            Error::DateParse { .. } => {
+                "dates must look like 2020-01-31T12:00:00Z; check the field above"
+            }
+            Error::UnexpectedSchema { .. } => {
+                "the library doesn't look like an iTunes export, or is from an unsupported version"
+            }
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Track fields holding dates, which get their own error variant
+const DATE_FIELDS: &[&str] = &["Date Modified", "Date Added", "Play Date UTC"];
+
+/// Load an "iTunes Library.xml" file, with errors pointing at the offending field.
+///
+/// This holds every track in memory at once; for big libraries, see `stream_library`.
+pub fn load_library<P: AsRef<Path>>(path: P) -> Result<Library> {
+    let events = plist::stream::Reader::new(BufReader::new(open_library(path.as_ref())?));
+    let mut deserializer = plist::Deserializer::new(events);
+    serde_path_to_error::deserialize(&mut deserializer).map_err(|err| classify_error("", err))
+}
+
+/// What `stream_library` does after handing a track to its callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    /// Hand over the next track
+    Track,
+
+    /// Count the rest of the tracks without parsing them, then read the rest of the library
+    SkipTracks,
+
+    /// Stop reading the file. Whatever comes after this track, like the playlists, is left out.
+    Stop,
+}
+
+/// Read the library at `path`, handing each track to `on_track` as soon as it's parsed, so only
+/// one track is in memory at a time. `on_track` says what to do next; see `Next`. Returns
+/// everything else in the library, with `tracks` left empty, and how many tracks were read,
+/// which is all of them unless `on_track` stopped early.
+pub fn stream_library<P, F, E>(path: P, mut on_track: F) -> std::result::Result<(Library, usize), E>
+where
+    P: AsRef<Path>,
+    F: FnMut(Track) -> std::result::Result<Next, E>,
+    E: From<Error>,
+{
+    let mut events = plist::stream::Reader::new(BufReader::new(open_library(path.as_ref())?));
+
+    // Everything but the tracks is kept as events, and deserialized at the end
+    let mut rest: Vec<Event> = Vec::new();
+    match next_event(&mut events)? {
+        Event::StartDictionary(len) => rest.push(Event::StartDictionary(len)),
+        _ => return Err(unexpected("", "expected a dictionary").into()),
+    }
+
+    let mut wanted = true;
+    let mut track_count: usize = 0;
+    loop {
+        match next_event(&mut events)? {
+            Event::EndCollection => {
+                rest.push(Event::EndCollection);
+                break;
+            }
+            Event::String(key) if key == "Tracks" => {
+                rest.push(Event::String(key));
+                rest.push(Event::StartDictionary(None));
+                rest.push(Event::EndCollection);
+
+                match next_event(&mut events)? {
+                    Event::StartDictionary(_) => {}
+                    _ => return Err(unexpected("Tracks", "expected a dictionary").into()),
+                }
+                loop {
+                    match next_event(&mut events)? {
+                        Event::EndCollection => break,
+                        Event::String(track_key) => {
+                            track_count += 1;
+                            if !wanted {
+                                skip_value(&mut events)?;
+                                continue;
+                            }
+
+                            let value = read_value(&mut events)?;
+                            match on_track(parse_track(&track_key, value)?)? {
+                                Next::Track => {}
+                                Next::SkipTracks => wanted = false,
+                                Next::Stop => {
+                                    rest.push(Event::EndCollection);
+                                    return finish_library(rest, track_count);
+                                }
+                            }
+                        }
+                        _ => return Err(unexpected("Tracks", "expected a track ID").into()),
+                    }
+                }
+            }
+            Event::String(key) => {
+                rest.push(Event::String(key));
+                rest.extend(read_value(&mut events)?);
+            }
+            _ => return Err(unexpected("", "expected a key").into()),
+        }
+    }
+
+    finish_library(rest, track_count)
+}
+
+/// Deserialize the non-track parts of a library kept by `stream_library`.
+fn finish_library<E: From<Error>>(
+    rest: Vec<Event>,
+    track_count: usize,
+) -> std::result::Result<(Library, usize), E> {
+    let mut deserializer = plist::Deserializer::new(rest.into_iter().map(Ok));
+    let library = serde_path_to_error::deserialize(&mut deserializer)
+        .map_err(|err| classify_error("", err))?;
+    Ok((library, track_count))
+}
+
+/// Stream just the tracks of the library at `path`; see `stream_library`.
+pub fn stream_tracks<P, F>(path: P, mut on_track: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(Track),
+{
+    stream_library(path, |track| -> Result<Next> {
+        on_track(track);
+        Ok(Next::Track)
+    })?;
+    Ok(())
+}
+
+fn open_library(path: &Path) -> Result<File> {
+    File::open(path).map_err(|source| match source.kind() {
+        io::ErrorKind::NotFound => Error::FileNotFound { path: path.into() },
+        _ => Error::ReadFailed {
+            path: path.into(),
+            source,
+        },
+    })
+}
+
+fn next_event<I>(events: &mut I) -> Result<Event>
+where
+    I: Iterator<Item = std::result::Result<Event, plist::Error>>,
+{
+    match events.next() {
+        Some(Ok(event)) => Ok(event),
+        Some(Err(err)) => Err(Error::PlistParse {
+            field: String::new(),
+            message: err.to_string(),
+        }),
+        None => Err(Error::PlistParse {
+            field: String::new(),
+            message: "unexpected end of file".into(),
+        }),
+    }
+}
+
+/// Read one whole value, which may be a collection several events long.
+fn read_value<I>(events: &mut I) -> Result<Vec<Event>>
+where
+    I: Iterator<Item = std::result::Result<Event, plist::Error>>,
+{
+    let mut value = Vec::new();
+    let mut depth: usize = 0;
+    loop {
+        let event = next_event(events)?;
+        match event {
+            Event::StartArray(_) | Event::StartDictionary(_) => depth += 1,
+            Event::EndCollection if depth == 0 => {
+                return Err(unexpected("", "collection ended before it started"));
+            }
+            Event::EndCollection => depth -= 1,
+            _ => {}
+        }
+        value.push(event);
+
+        if depth == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Read past one whole value like `read_value`, dropping its events as they're read.
+fn skip_value<I>(events: &mut I) -> Result<()>
+where
+    I: Iterator<Item = std::result::Result<Event, plist::Error>>,
+{
+    let mut depth: usize = 0;
+    loop {
+        match next_event(events)? {
+            Event::StartArray(_) | Event::StartDictionary(_) => depth += 1,
+            Event::EndCollection if depth == 0 => {
+                return Err(unexpected("", "collection ended before it started"));
+            }
+            Event::EndCollection => depth -= 1,
+            _ => {}
+        }
+
+        if depth == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn parse_track(key: &str, events: Vec<Event>) -> Result<Track> {
+    let mut deserializer = plist::Deserializer::new(events.into_iter().map(Ok));
+    serde_path_to_error::deserialize(&mut deserializer)
+        .map_err(|err| classify_error(&format!("Tracks.{}", key), err))
+}
+
+fn unexpected(field: &str, message: &str) -> Error {
+    Error::UnexpectedSchema {
+        field: field.into(),
+        message: message.into(),
+    }
+}
+
+/// Sort a deserialization failure into one of our errors. `within` is the path of the value
+/// being deserialized, if it isn't the whole library.
+fn classify_error(within: &str, err: serde_path_to_error::Error<plist::Error>) -> Error {
+    let field = match within {
+        "" => err.path().to_string(),
+        within => format!("{}.{}", within, err.path()),
+    };
+    let is_date = match err.path().iter().last() {
+        Some(Segment::Map { key }) => DATE_FIELDS.contains(&key.as_str()),
+        _ => false,
+    };
+    let message = err.into_inner().to_string();
+
+    let is_schema = ["missing field", "invalid type", "invalid value", "unknown"]
+        .iter()
+        .any(|prefix| message.starts_with(prefix));
+
+    if is_date {
+        Error::DateParse { field, message }
+    } else if is_schema {
+        Error::UnexpectedSchema { field, message }
+    } else {
+        Error::PlistParse { field, message }
+    }
+}
+
+/// The library's own header fields are optional, since partial exports can leave them out.
 #[derive(Serialize, Deserialize)]
 pub struct Library {
     #[serde(rename = "Application Version")]
-    pub application_version: String,
+    pub application_version: Option<String>,
 
     #[serde(rename = "Library Persistent ID")]
-    pub persistent_id: String,
+    pub persistent_id: Option<String>,
 
     #[serde(rename = "Date")]
-    pub date: String,
+    pub date: Option<String>,
 
     #[serde(rename = "Tracks")]
     pub tracks: HashMap<String, Track>,
+
+    #[serde(rename = "Playlists", default)]
+    pub playlists: Vec<Playlist>,
 }
 
 generate_id!(TrackID);
@@ -67,4 +351,47 @@ pub struct Track {
 
     #[serde(rename = "Kind")]
     pub kind: Option<String>,
+
+    #[serde(rename = "Loved")]
+    pub loved: Option<bool>,
+
+    /// From 0 to 100: 20 per star
+    #[serde(rename = "Rating")]
+    pub rating: Option<u32>,
+
+    #[serde(rename = "Skip Count")]
+    pub skip_count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Playlist {
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Playlist Persistent ID")]
+    pub persistent_id: String,
+
+    #[serde(rename = "Playlist Items", default)]
+    pub items: Vec<PlaylistItem>,
+
+    /// Set on the "Library" playlist, which holds every track
+    #[serde(rename = "Master", default)]
+    pub master: bool,
+
+    /// Set on iTunes' own playlists, like "Music" and "Downloaded"
+    #[serde(rename = "Distinguished Kind")]
+    pub distinguished_kind: Option<u64>,
+}
+
+impl Playlist {
+    /// Whether this is one of the playlists iTunes makes itself, rather than one the user made.
+    pub fn is_builtin(&self) -> bool {
+        self.master || self.distinguished_kind.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlaylistItem {
+    #[serde(rename = "Track ID")]
+    pub track_id: TrackID,
 }
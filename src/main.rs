@@ -1,25 +1,54 @@
 use clap::Clap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
 use std::time::{Duration, Instant};
 
 #[macro_use]
 mod id;
 
+#[cfg(feature = "asynced")]
+pub mod asynced;
+pub mod diff;
+pub mod federated;
 pub mod itunes;
+#[cfg(feature = "serve")]
+pub mod serve;
 pub mod store;
 
-use store::{ItemID, Store};
+use id::Id;
+use store::{ItemID, Store, StoreOptions};
 
 #[derive(Clap)]
 struct Opts {
+    /// The store path is taken from `--store` if given, then from `$TAGMU_STORE`, and finally
+    /// defaults to `store.tagmu` in the working directory.
     #[clap(
         long = "store",
         short = "s",
         help = "Path to the tagmu store",
+        env = "TAGMU_STORE",
         default_value = "store.tagmu"
     )]
     store_path: String,
 
+    #[clap(
+        long = "cache-mb",
+        help = "Megabytes of cache for the store",
+        default_value = "10"
+    )]
+    cache_mb: u64,
+
+    #[clap(
+        long = "id-format",
+        help = "How find and item print IDs: \"dec\", or \"hex\" to match compound keys",
+        default_value = "dec",
+        possible_values = &["dec", "hex"]
+    )]
+    id_format: IdFormat,
+
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -28,105 +57,1712 @@ struct Opts {
 enum Command {
     Import(Import),
     Find(Find),
+    StaleTags(StaleTags),
+    Query(Query),
+    MergeTags(MergeTags),
+    Tag(Tag),
+    TagAll(TagAll),
+    Untag(Untag),
+    Tags(Tags),
+    Complete(Complete),
+    Item(Item),
+    Diff(Diff),
+    History,
+    CompactItems,
+    Health,
+    Verify,
+    Repair(Repair),
+    #[cfg(feature = "serve")]
+    Serve(Serve),
+    Stats,
+    Export(Export),
+    ImportNdjson(ImportNdjson),
+    ImportCsv(ImportCsv),
+    ImportDir(ImportDir),
+    Backup(Backup),
+    Restore(Restore),
+    Flush,
+}
+
+impl Command {
+    /// Whether the command only reads the store, so it can be opened read-only.
+    fn is_read_only(&self) -> bool {
+        match self {
+            Command::Import(_)
+            | Command::Tag(_)
+            | Command::TagAll(_)
+            | Command::Untag(_)
+            | Command::CompactItems
+            | Command::ImportNdjson(_)
+            | Command::ImportCsv(_)
+            | Command::ImportDir(_) => false,
+            Command::MergeTags(args) => args.dry_run,
+            Command::Repair(args) => args.dry_run,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Clap)]
 struct Import {
     #[clap(long = "library", help = "Path to the \"iTunes Library.xml\" file")]
     itunes_library: String,
+
+    #[clap(
+        long = "rename-map",
+        help = "Path to a file of \"from<TAB>to\" lines used to rewrite tag values"
+    )]
+    rename_map: Option<String>,
+
+    #[clap(
+        long = "sample",
+        help = "Only import the first N tracks, for quick smoke tests"
+    )]
+    sample: Option<usize>,
+
+    #[clap(
+        long = "skip-threshold",
+        help = "Tag tracks skipped more than this many times as \"often-skipped\"",
+        default_value = "5"
+    )]
+    skip_threshold: u32,
+
+    #[clap(
+        long = "kinds",
+        help = "Only import tracks whose Kind contains one of these, or whose Track Type is one",
+        use_delimiter = true,
+        default_value = "audio"
+    )]
+    kinds: Vec<String>,
+
+    #[clap(
+        long = "tag-excluded",
+        help = "Still add tracks of other kinds, tagged only \"kind:<Kind>\", dropping any tags an earlier import gave them"
+    )]
+    tag_excluded: bool,
 }
 
+/// Write every tagged item as one line of JSON.
+#[derive(Clap)]
+struct Export {
+    #[clap(
+        long = "tag-index",
+        help = "Write one \"tag<TAB>item,item,...\" line per tag instead"
+    )]
+    tag_index: bool,
+}
+
+/// Import items from `export` output.
+#[derive(Clap)]
+struct ImportNdjson {
+    #[clap(help = "Path to the NDJSON file, or \"-\" for stdin")]
+    path: String,
+}
+
+/// Import one item per row of a CSV file with a header row. Every cell except the name becomes
+/// a "column:value" tag; empty cells are skipped.
+#[derive(Clap)]
+struct ImportCsv {
+    #[clap(help = "Path to the CSV file")]
+    path: String,
+
+    #[clap(
+        long = "name-column",
+        help = "Column holding each item's display name",
+        default_value = "name"
+    )]
+    name_column: String,
+}
+
+/// Import one item per file under a directory, tagged with its extension ("ext:mp3") and the
+/// directories it's in ("dir:Jazz"). Hidden files and directories are skipped.
+#[derive(Clap)]
+struct ImportDir {
+    #[clap(help = "Directory to scan")]
+    root: String,
+}
+
+/// Remove the broken index entries `verify` finds.
+#[derive(Clap)]
+struct Repair {
+    #[clap(long = "dry-run", help = "Only list what the repair would remove")]
+    dry_run: bool,
+}
+
+/// Answer read-only queries over HTTP, with JSON responses. See the `serve` module for the
+/// endpoints.
+#[cfg(feature = "serve")]
+#[derive(Clap)]
+struct Serve {
+    #[clap(help = "Address to listen on", default_value = "127.0.0.1:8080")]
+    addr: std::net::SocketAddr,
+}
+
+/// Save a copy of the store to a single file.
+#[derive(Clap)]
+struct Backup {
+    #[clap(help = "Where to write the backup")]
+    file: String,
+}
+
+/// Restore a backup made with `backup` into the store.
+#[derive(Clap)]
+struct Restore {
+    #[clap(help = "Backup file to restore")]
+    file: String,
+
+    #[clap(long = "force", help = "Replace whatever is already in the store")]
+    force: bool,
+}
+
+/// Find all items with a tag.
+///
+/// Like grep, exits 0 if any items were found, 1 if the tag exists but has no items, and 2 if
+/// there is no such tag.
 #[derive(Clap)]
 struct Find {
     query: String,
+
+    #[clap(long = "count-only", help = "Only print how many items match")]
+    count_only: bool,
+
+    #[clap(long = "favorites-only", help = "Only show favorited items")]
+    favorites_only: bool,
+
+    #[clap(
+        long = "where",
+        number_of_values = 1,
+        help = "Only show items with this attribute, e.g. \"location=file:///a.mp3\"",
+        parse(try_from_str = parse_attr_filter)
+    )]
+    filters: Vec<(String, String)>,
+
+    #[clap(
+        long = "where-counter",
+        number_of_values = 1,
+        help = "Only show items whose numeric attribute matches, e.g. \"play_count=3\"",
+        parse(try_from_str = parse_counter_filter)
+    )]
+    counter_filters: Vec<(String, i64)>,
+
+    #[clap(
+        long = "offset",
+        help = "Skip this many matching items before printing",
+        default_value = "0"
+    )]
+    offset: usize,
+
+    #[clap(long = "limit", help = "Print at most this many items")]
+    limit: Option<usize>,
+
+    #[clap(
+        long = "after",
+        help = "Start after this item ID, e.g. the last one on the previous page"
+    )]
+    after: Option<u64>,
+
+    #[clap(
+        long = "json",
+        help = "Print each item as a line of JSON, with the summary on stderr"
+    )]
+    json: bool,
+
+    #[clap(
+        long = "ids-only",
+        help = "Print only item IDs, one per line, with the summary on stderr",
+        conflicts_with = "json"
+    )]
+    ids_only: bool,
+}
+
+/// Find items by combining tags: (all of `--all`) and (any of `--any`), minus any of `--none`.
+#[derive(Clap)]
+struct Query {
+    #[clap(long = "all", number_of_values = 1, help = "Items must have this tag")]
+    all: Vec<String>,
+
+    #[clap(
+        long = "any",
+        number_of_values = 1,
+        help = "Items must have at least one of these tags"
+    )]
+    any: Vec<String>,
+
+    #[clap(
+        long = "none",
+        number_of_values = 1,
+        help = "Items must not have this tag"
+    )]
+    none: Vec<String>,
+}
+
+/// Merge one tag into another.
+#[derive(Clap)]
+struct MergeTags {
+    #[clap(help = "Tag to merge away")]
+    from: String,
+
+    #[clap(help = "Tag to merge into")]
+    into: String,
+
+    #[clap(long = "dry-run", help = "Only report what the merge would do")]
+    dry_run: bool,
+}
+
+/// Tag an item by name, creating any tags that don't exist yet.
+#[derive(Clap)]
+struct Tag {
+    #[clap(
+        long = "item",
+        help = "ID of the item to tag, or \"-\" to read IDs from stdin"
+    )]
+    item: String,
+
+    #[clap(required = true, help = "Names of the tags to apply")]
+    tags: Vec<String>,
+}
+
+/// Apply one tag to every item whose ID is read from stdin, e.g. from `find --ids-only`. IDs
+/// are separated by whitespace or newlines.
+#[derive(Clap)]
+struct TagAll {
+    #[clap(
+        long = "tag",
+        help = "Name of the tag to apply, created if it doesn't exist"
+    )]
+    tag: String,
+}
+
+/// Remove tags from an item by name.
+#[derive(Clap)]
+struct Untag {
+    #[clap(
+        long = "item",
+        help = "ID of the item to untag, or \"-\" to read IDs from stdin"
+    )]
+    item: String,
+
+    #[clap(required = true, help = "Names of the tags to remove")]
+    tags: Vec<String>,
+}
+
+/// List tags with how many items have each, most used first.
+#[derive(Clap)]
+struct Tags {
+    #[clap(long = "limit", help = "Only show the N most used tags")]
+    limit: Option<usize>,
+
+    #[clap(long = "prefix", help = "Only show tags whose names start with this")]
+    prefix: Option<String>,
+
+    #[clap(
+        long = "created",
+        help = "Show when each tag was created, and list the newest first"
+    )]
+    created: bool,
+}
+
+/// Print the names of tags starting with a prefix, one per line, for shell completion.
+#[derive(Clap)]
+struct Complete {
+    #[clap(help = "Start of the tag name")]
+    prefix: String,
+
+    #[clap(long = "limit", default_value = "50", help = "Print at most N names")]
+    limit: usize,
+}
+
+/// Show every tag on an item.
+///
+/// Exits 1 if the item has no tags.
+#[derive(Clap)]
+struct Item {
+    #[clap(help = "ID of the item")]
+    item: u64,
+
+    #[clap(long = "json", help = "Print the tags as JSON")]
+    json: bool,
+}
+
+/// Compare this store against another, e.g. a copy that was re-imported.
+#[derive(Clap)]
+struct Diff {
+    #[clap(help = "Path to the other tagmu store")]
+    other: String,
+
+    #[clap(long = "detailed", help = "List every change, not just the counts")]
+    detailed: bool,
+}
+
+/// List tags that haven't been applied to any item recently.
+#[derive(Clap)]
+struct StaleTags {
+    #[clap(
+        long = "older-than",
+        help = "Report tags unused for this long, e.g. \"30d\", \"12h\", \"2w\"",
+        parse(try_from_str = parse_duration)
+    )]
+    older_than: chrono::Duration,
+}
+
+/// How many (item, tag) pairs the importer queues before writing them out.
+const IMPORT_BATCH: usize = 5000;
+
+/// How many similar tag names `find` suggests when the tag doesn't exist.
+const FIND_SUGGESTIONS: usize = 5;
+
+/// How IDs are printed in human-readable output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdFormat {
+    Decimal,
+    Hex,
+}
+
+impl std::str::FromStr for IdFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<IdFormat, String> {
+        match text {
+            "dec" => Ok(IdFormat::Decimal),
+            "hex" => Ok(IdFormat::Hex),
+            _ => Err(format!(
+                "\"{}\" isn't an ID format; use \"dec\" or \"hex\"",
+                text
+            )),
+        }
+    }
+}
+
+impl IdFormat {
+    /// Format `id` for a column: right-aligned decimal, or fixed-width hex.
+    fn show<I: Id>(self, id: I) -> String {
+        match self {
+            IdFormat::Decimal => format!("{:10}", id),
+            IdFormat::Hex => id.to_hex(),
+        }
+    }
+}
+
+/// How a command finished, which determines the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    NoResults,
+    UnknownTag,
+    Inconsistent,
+}
+
+impl Outcome {
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::NoResults => 1,
+            Outcome::UnknownTag => 2,
+            Outcome::Inconsistent => 3,
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opts: Opts = Opts::parse();
 
+    let outcome = run(opts)?;
+    if outcome != Outcome::Success {
+        std::process::exit(outcome.exit_code());
+    }
+
+    Ok(())
+}
+
+fn run(opts: Opts) -> Result<Outcome, Box<dyn Error>> {
+    // Restoring opens the store itself, once it's been written
+    if let Command::Restore(args) = &opts.cmd {
+        let restore_start = Instant::now();
+        let store = Store::restore(Path::new(&args.file), opts.store_path, args.force)?;
+        println!(
+            "restored {} tags in {}ms",
+            store.tag_count(),
+            restore_start.elapsed().as_millis()
+        );
+        return Ok(Outcome::Success);
+    }
+
     let open_start = Instant::now();
-    let mut store = Store::open(opts.store_path)?;
+    let options = StoreOptions {
+        cache_capacity: opts.cache_mb * 1000 * 1000,
+        read_only: opts.cmd.is_read_only(),
+        ..StoreOptions::default()
+    };
+    let store = Store::open_with(opts.store_path, options.clone())?;
     let open_ms = open_start.elapsed().as_millis();
-    println!("opened store in {:.0}ms", open_ms);
+    // This goes to stderr so that commands like `export` can be piped
+    eprintln!("opened store in {:.0}ms", open_ms);
 
     match opts.cmd {
         Command::Import(load) => {
-            let Import { itunes_library } = load;
+            let Import {
+                itunes_library,
+                rename_map,
+                sample,
+                skip_threshold,
+                kinds,
+                tag_excluded,
+            } = load;
             println!("tagmu load");
 
-            println!("Loading library from '{}'", itunes_library);
-            let library: itunes::Library = plist::from_file(itunes_library)?;
-            println!(
-                "Loaded libray, version:{} track_count:{}",
-                library.application_version,
-                library.tracks.len()
-            );
+            let rename_map = match rename_map {
+                Some(path) => {
+                    println!("Loading rename map from '{}'", path);
+                    load_rename_map(&path)?
+                }
+                None => HashMap::new(),
+            };
 
-            println!("Indexing library...");
-            for track in library.tracks.values() {
-                let item: ItemID = store.id()?.into();
+            println!("Indexing library from '{}'...", itunes_library);
+            let tags_before = store.tag_count();
+            let mut indexed: usize = 0;
+            let mut excluded: usize = 0;
+            let (mut added, mut updated, mut unchanged): (usize, usize, usize) = (0, 0, 0);
+            let old_numbers = store.numeric_tags_by_item()?;
+            let mut track_items: HashMap<itunes::TrackID, ItemID> = HashMap::new();
+            let mut bach: Vec<String> = Vec::new();
+            let mut pending: Vec<(ItemID, String)> = Vec::new();
+            let index_start = Instant::now();
 
-                // Tag some things about this entity
-                if let Some(album) = &track.album {
-                    store.tag_string(item, &album)?;
+            // Tracks are indexed as they're read, so the library is never all in memory. Once
+            // the sample is full, reading stops there, so a small sample of a big library is
+            // quick; the playlists come after the tracks, so they're skipped too.
+            let sample_size = sample.unwrap_or(usize::MAX);
+            let mut stopped = false;
+            let on_track = |track: itunes::Track| -> Result<itunes::Next, Box<dyn Error>> {
+                if indexed >= sample_size {
+                    stopped = true;
+                    return Ok(itunes::Next::Stop);
                 }
-                if let Some(artist) = &track.artist {
-                    store.tag_string(item, &artist)?;
+                if track.composer == Some("Bach".into()) {
+                    bach.push(format!(
+                        "id:{} name={}",
+                        track.id,
+                        track.name.as_ref().unwrap_or(&"<no name>".into())
+                    ));
                 }
-                if let Some(composer) = &track.composer {
-                    store.tag_string(item, &composer)?;
+
+                if !kind_matches(&track, &kinds) {
+                    excluded += 1;
+                    if tag_excluded {
+                        index_excluded_track(&store, &track, &old_numbers, &mut pending)?;
+                    }
+                } else {
+                    let (item, change) = index_track(
+                        &store,
+                        &track,
+                        &rename_map,
+                        skip_threshold,
+                        &old_numbers,
+                        &mut pending,
+                    )?;
+                    match change {
+                        TrackChange::Added => added += 1,
+                        TrackChange::Updated => updated += 1,
+                        TrackChange::Unchanged => unchanged += 1,
+                    }
+                    track_items.insert(track.id, item);
+                    indexed += 1;
+
+                    if pending.len() >= IMPORT_BATCH {
+                        flush_tags(&store, &mut pending)?;
+                    }
                 }
-                if let Some(genre) = &track.genre {
-                    store.tag_string(item, &genre)?;
+                if indexed >= sample_size {
+                    stopped = true;
+                    return Ok(itunes::Next::Stop);
                 }
-                if let Some(year) = &track.year {
-                    store.tag_string(item, &format!("{}", year))?;
+                Ok(itunes::Next::Track)
+            };
+            let streamed = itunes::stream_library(&itunes_library, on_track);
+            let (library, total) = match streamed {
+                Ok(streamed) => streamed,
+                Err(e) => {
+                    if let Some(e) = e.downcast_ref::<itunes::Error>() {
+                        eprintln!("Couldn't load library: {}", e);
+                        eprintln!("hint: {}", e.hint());
+                    }
+                    return Err(e);
+                }
+            };
+            println!(
+                "Loaded libray, version:{} track_count:{}{}",
+                library.application_version.as_deref().unwrap_or("unknown"),
+                if stopped { "at least " } else { "" },
+                total
+            );
+            if stopped {
+                println!(
+                    "Done indexing (sampled {} tracks, and stopped reading there, so the rest \
+                     of the tracks and the playlists were skipped).",
+                    indexed
+                );
+            } else if sample.is_some() {
+                println!("Done indexing (sampled {} of {} tracks).", indexed, total);
+            } else {
+                println!("Done indexing.");
+            }
+            println!(
+                "{} tracks added, {} updated, {} unchanged.",
+                added, updated, unchanged
+            );
+            if excluded > 0 {
+                println!(
+                    "{} {} tracks that aren't of kind {}.",
+                    if tag_excluded { "Tagged" } else { "Skipped" },
+                    excluded,
+                    kinds.join(", ")
+                );
+            }
+
+            // Playlists become tags on their tracks
+            let mut playlists: usize = 0;
+            let mut playlist_tags: HashMap<ItemID, BTreeSet<String>> = HashMap::new();
+            for playlist in library.playlists.iter().filter(|p| !p.is_builtin()) {
+                let tag_name = format!("{}{}", PLAYLIST_TAG_PREFIX, playlist.name);
+                for playlist_item in &playlist.items {
+                    if let Some(&item) = track_items.get(&playlist_item.track_id) {
+                        playlist_tags
+                            .entry(item)
+                            .or_default()
+                            .insert(tag_name.clone());
+                    }
                 }
-                if let Some(name) = &track.name {
-                    store.tag_string(item, &name)?;
+                playlists += 1;
+            }
+            // If reading stopped early there are no playlists to compare with, so the tracks keep
+            // the playlist tags they had
+            if !stopped {
+                for &item in track_items.values() {
+                    let tags = playlist_tags.remove(&item).unwrap_or_default();
+                    update_playlist_tags(&store, item, tags, &mut pending)?;
                 }
             }
-            println!("Done indexing.");
+            flush_tags(&store, &mut pending)?;
+            println!("Tagged tracks from {} playlists.", playlists);
+
+            // Time the import up to when it's on disk, not just buffered
+            store.flush()?;
+            println!(
+                "indexed {} tracks in {}ms",
+                indexed,
+                index_start.elapsed().as_millis()
+            );
+
+            let tags_created = store.tag_count().saturating_sub(tags_before);
+            println!("Created {} new tags.", tags_created);
+
+            store.record_import(&store::ImportRecord {
+                time: chrono::Utc::now(),
+                library_id: library
+                    .persistent_id
+                    .clone()
+                    .unwrap_or_else(|| "unknown".to_string()),
+                items: indexed as u64,
+                tags_created: tags_created as u64,
+            })?;
 
             println!("looking for Bach...");
-            for (_, track) in library.tracks.iter() {
-                if track.composer == Some("Bach".into()) {
-                    println!(
-                        "id:{} name={}",
-                        track.id,
-                        track.name.as_ref().unwrap_or(&"<no name>".into())
-                    );
-                }
+            for line in &bach {
+                println!("{}", line);
             }
             println!("Done.");
 
-            Ok(())
+            Ok(Outcome::Success)
         }
         Command::Find(args) => {
-            println!("find: all items with tag \"{}\"", args.query);
+            // With --json or --ids-only, only the items go to stdout
+            let summary = |line: String| {
+                if args.json || args.ids_only {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            };
+            summary(format!("find: all items with tag \"{}\"", args.query));
 
             // Get the tag
-            let tag_id: store::TagID = store.get_tag_id(&args.query)?.ok_or("Couldn't find tag")?;
+            let tag_id: store::TagID = match store.get_tag_id(&args.query)? {
+                Some(tag_id) => tag_id,
+                None => {
+                    eprintln!("Couldn't find tag \"{}\"", args.query);
+                    let suggestions = store.search_tags(&args.query)?;
+                    if !suggestions.is_empty() {
+                        let names: Vec<String> = suggestions
+                            .iter()
+                            .take(FIND_SUGGESTIONS)
+                            .map(|tag| format!("\"{}\"", tag.name))
+                            .collect();
+                        eprintln!("did you mean {}?", names.join(", "));
+                    }
+                    return Ok(Outcome::UnknownTag);
+                }
+            };
 
             let query_start = Instant::now();
+            if args.count_only
+                && !args.favorites_only
+                && args.filters.is_empty()
+                && args.counter_filters.is_empty()
+                && args.after.is_none()
+            {
+                let count = store.tag_item_count(tag_id)?;
+                summary(format!(
+                    "got {} items in {}ms",
+                    count,
+                    query_start.elapsed().as_millis()
+                ));
+
+                return Ok(if count == 0 {
+                    Outcome::NoResults
+                } else {
+                    Outcome::Success
+                });
+            }
+
+            // Seeking to --after skips the items before it without reading them
+            let items = match args.after {
+                Some(after) => Box::new(store.get_tag_item_ids_after(tag_id, after.into()))
+                    as Box<dyn Iterator<Item = Result<ItemID, store::Error>> + '_>,
+                None => Box::new(store.get_tag_item_ids(tag_id))
+                    as Box<dyn Iterator<Item = Result<ItemID, store::Error>> + '_>,
+            };
+            let limit = args.limit.unwrap_or(usize::MAX);
+
             let mut count: usize = 0;
-            for item_result in store.get_tag_item_ids(tag_id) {
+            'items: for item_result in items {
                 let item_id = item_result?;
 
+                // Narrow down by the other indices
+                if args.favorites_only && !store.is_favorite(item_id)? {
+                    continue;
+                }
+                for (key, value) in &args.filters {
+                    // A counter isn't text, so it never matches
+                    if store.get_item_attr_text(item_id, key)?.as_ref() != Some(value) {
+                        continue 'items;
+                    }
+                }
+                for (key, value) in &args.counter_filters {
+                    if store.get_item_counter(item_id, key)? != Some(*value) {
+                        continue 'items;
+                    }
+                }
+
+                count += 1;
+                if args.count_only || count <= args.offset || count - args.offset > limit {
+                    continue;
+                }
+
+                if args.ids_only {
+                    println!("{}", item_id);
+                    continue;
+                }
+
+                let item_tags = store
+                    .get_item_tags(item_id)
+                    .collect::<Result<Vec<store::Tag>, _>>()?;
+
+                if args.json {
+                    let names: Vec<&str> = item_tags.iter().map(|tag| tag.name.as_str()).collect();
+                    let line = serde_json::json!({
+                        "item": item_id,
+                        "name": store.get_item_meta(item_id)?,
+                        "tags": names,
+                    });
+                    println!("{}", line);
+                    continue;
+                }
+
+                match store.get_item_meta(item_id)? {
+                    Some(name) => println!(
+                        "{}:\"{}\" {:?}",
+                        opts.id_format.show(item_id),
+                        name,
+                        item_tags
+                    ),
+                    None => println!("{}:{:?}", opts.id_format.show(item_id), item_tags),
+                }
+            }
+            summary(format!(
+                "got {} items in {}ms",
+                count,
+                query_start.elapsed().as_millis()
+            ));
+
+            if count == 0 {
+                Ok(Outcome::NoResults)
+            } else {
+                Ok(Outcome::Success)
+            }
+        }
+        Command::Query(args) => {
+            if args.all.is_empty() && args.any.is_empty() {
+                return Err("query needs at least one --all or --any tag".into());
+            }
+
+            let query_start = Instant::now();
+
+            // Unknown tags can't be on any item
+            let all = resolve_tags(&store, &args.all)?;
+            let any = resolve_tags(&store, &args.any)?;
+            let none = resolve_tags(&store, &args.none)?;
+            let impossible = all.len() < args.all.len() || (!args.any.is_empty() && any.is_empty());
+
+            let matched: Vec<ItemID> = if impossible {
+                Vec::new()
+            } else {
+                store
+                    .query(&all, &any, &none)
+                    .collect::<Result<Vec<ItemID>, _>>()?
+            };
+
+            for &item_id in &matched {
                 let item_tags = store
                     .get_item_tags(item_id)
                     .collect::<Result<Vec<store::Tag>, _>>()?;
 
                 println!("{:10}:{:?}", item_id, item_tags);
-                count += 1;
             }
             println!(
                 "got {} items in {}ms",
-                count,
+                matched.len(),
                 query_start.elapsed().as_millis()
             );
 
-            Ok(())
+            if matched.is_empty() {
+                Ok(Outcome::NoResults)
+            } else {
+                Ok(Outcome::Success)
+            }
+        }
+        Command::MergeTags(args) => {
+            let from = store
+                .get_tag_id(&args.from)?
+                .ok_or("Couldn't find source tag")?;
+            let into = store
+                .get_tag_id(&args.into)?
+                .ok_or("Couldn't find destination tag")?;
+
+            if !args.dry_run {
+                let moved = store.merge_tags(from, into)?;
+                println!(
+                    "merged \"{}\" into \"{}\", moving {} items",
+                    args.from, args.into, moved
+                );
+                return Ok(Outcome::Success);
+            }
+
+            let preview = store.merge_tags_preview(from, into)?;
+            println!(
+                "merge \"{}\" into \"{}\": {} items would move, {} already have both",
+                args.from, args.into, preview.moved, preview.shared
+            );
+            for field in &preview.lost_metadata {
+                println!("  would lose metadata: {}", field);
+            }
+
+            Ok(Outcome::Success)
         }
+        Command::Tag(args) => {
+            let items = parse_item_arg(&args.item)?;
+
+            // Resolve each name once, in order, so a repeated name is only created once
+            let mut names: Vec<&str> = Vec::new();
+            for name in &args.tags {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+            let mut created: usize = 0;
+            for name in &names {
+                let (tag_id, was_created) = store.get_or_create_tag(name)?;
+                if was_created {
+                    created += 1;
+                }
+                println!(
+                    "{:10}:\"{}\" ({})",
+                    tag_id,
+                    name,
+                    if was_created { "created" } else { "existing" }
+                );
+            }
+
+            for &item in &items {
+                store.tag_all(item, &names)?;
+            }
+            println!(
+                "tagged {} with {} tags ({} new)",
+                describe_items(&items),
+                names.len(),
+                created
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::TagAll(args) => {
+            let items = read_item_ids(io::stdin().lock())?;
+            let (tag_id, _) = store.get_or_create_tag(&args.tag)?;
+
+            let mut added: usize = 0;
+            for chunk in items.chunks(IMPORT_BATCH) {
+                added += store.tag_items(chunk, tag_id)?;
+            }
+            println!(
+                "tagged {} items \"{}\" ({} already had it)",
+                items.len(),
+                args.tag,
+                items.len() - added
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::Untag(args) => {
+            let items = parse_item_arg(&args.item)?;
+
+            let mut tags: Vec<(&str, store::TagID)> = Vec::new();
+            let mut unknown: usize = 0;
+            for name in &args.tags {
+                match store.get_tag_id(name)? {
+                    Some(tag_id) => tags.push((name, tag_id)),
+                    None => {
+                        eprintln!("Couldn't find tag \"{}\"", name);
+                        unknown += 1;
+                    }
+                }
+            }
+
+            let mut removed: usize = 0;
+            for &item in &items {
+                for &(name, tag_id) in &tags {
+                    if store.untag(item, tag_id)? {
+                        removed += 1;
+                    } else {
+                        println!("item {} wasn't tagged \"{}\"", item, name);
+                    }
+                }
+            }
+            println!(
+                "removed {} tags from {} ({} unknown)",
+                removed,
+                describe_items(&items),
+                unknown
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::Tags(args) => {
+            let prefix = args.prefix.as_deref().unwrap_or("");
+
+            let mut counted: Vec<(u64, Option<chrono::DateTime<chrono::Utc>>, store::Tag)> =
+                Vec::new();
+            for tag_result in store.all_tags() {
+                let tag = tag_result?;
+                if !tag.name.starts_with(prefix) {
+                    continue;
+                }
+                let created = if args.created {
+                    store.tag_created_at(tag.id)?
+                } else {
+                    None
+                };
+                counted.push((store.tag_item_count(tag.id)?, created, tag));
+            }
+            counted.sort_by(|(a_count, a_created, a), (b_count, b_created, b)| {
+                // Tags with no recorded creation time sort last, as None < Some
+                b_created
+                    .cmp(a_created)
+                    .then_with(|| b_count.cmp(a_count))
+                    .then_with(|| a.name.cmp(&b.name))
+            });
+
+            let total = counted.len();
+            for (count, created, tag) in counted.iter().take(args.limit.unwrap_or(usize::MAX)) {
+                match (args.created, created) {
+                    (true, Some(created)) => println!(
+                        "{:10}:{} ({} items, created {})",
+                        tag.id, tag.name, count, created
+                    ),
+                    (true, None) => println!(
+                        "{:10}:{} ({} items, created before tracking)",
+                        tag.id, tag.name, count
+                    ),
+                    (false, _) => println!("{:10}:{} ({} items)", tag.id, tag.name, count),
+                }
+            }
+            println!("got {} tags", total);
+
+            Ok(Outcome::Success)
+        }
+        Command::Complete(args) => {
+            for tag in store.tags_with_prefix(&args.prefix, args.limit)? {
+                println!("{}", tag.name);
+            }
+            Ok(Outcome::Success)
+        }
+        Command::Item(args) => {
+            let item = ItemID::from(args.item);
+            let item_tags = store
+                .get_item_tags(item)
+                .collect::<Result<Vec<store::Tag>, _>>()?;
+
+            if item_tags.is_empty() {
+                if store.item_has_attrs(item)? {
+                    eprintln!("item {} has no tags", item);
+                } else {
+                    eprintln!("Couldn't find item {}: it has no tags or attributes", item);
+                }
+                return Ok(Outcome::NoResults);
+            }
+
+            if args.json {
+                let output = serde_json::json!({ "item": args.item, "tags": item_tags });
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            } else {
+                for tag in &item_tags {
+                    println!("{}:{}", opts.id_format.show(tag.id), tag.name);
+                }
+            }
+
+            Ok(Outcome::Success)
+        }
+        Command::Diff(args) => {
+            let other = Store::open_with(
+                args.other.clone(),
+                StoreOptions {
+                    read_only: true,
+                    ..options
+                },
+            )?;
+
+            let diff_start = Instant::now();
+            let changes = diff::diff(&store, &other)?;
+            println!("diff: changes from this store to '{}'", args.other);
+            println!(
+                "tags: +{} -{}",
+                changes.tags_added.len(),
+                changes.tags_removed.len()
+            );
+            println!(
+                "items: +{} -{}",
+                changes.items_added.len(),
+                changes.items_removed.len()
+            );
+            println!(
+                "associations: +{} -{}",
+                changes.associations_added.len(),
+                changes.associations_removed.len()
+            );
+
+            if args.detailed {
+                for name in &changes.tags_added {
+                    println!("+tag {}", name);
+                }
+                for name in &changes.tags_removed {
+                    println!("-tag {}", name);
+                }
+                for item in &changes.items_added {
+                    println!("+item {}", item);
+                }
+                for item in &changes.items_removed {
+                    println!("-item {}", item);
+                }
+                for (item, name) in &changes.associations_added {
+                    println!("+{:10}:{}", item, name);
+                }
+                for (item, name) in &changes.associations_removed {
+                    println!("-{:10}:{}", item, name);
+                }
+            }
+            println!("diffed in {}ms", diff_start.elapsed().as_millis());
+
+            Ok(Outcome::Success)
+        }
+        Command::CompactItems => {
+            let compact_start = Instant::now();
+            let mapping = store.compact_item_ids()?;
+            let moved = mapping.iter().filter(|(old, new)| old != new).count();
+            println!(
+                "renumbered {} of {} items in {}ms",
+                moved,
+                mapping.len(),
+                compact_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::Health => {
+            let health = store.health_json()?;
+            println!("{}", serde_json::to_string_pretty(&health)?);
+
+            Ok(Outcome::Success)
+        }
+        Command::Verify => {
+            let verify_start = Instant::now();
+            let report = store.verify()?;
+
+            println!("missing reverse entries: {}", report.missing_reverse);
+            println!("missing forward entries: {}", report.missing_forward);
+            println!("entries for unnamed tags: {}", report.unnamed_tags);
+            println!(
+                "names missing from the name index: {}",
+                report.unindexed_names
+            );
+            println!("stale names in the name index: {}", report.stale_names);
+            println!("verified in {}ms", verify_start.elapsed().as_millis());
+
+            if report.is_consistent() {
+                Ok(Outcome::Success)
+            } else {
+                Ok(Outcome::Inconsistent)
+            }
+        }
+        Command::Repair(args) => {
+            let report = if args.dry_run {
+                store.repair_preview()?
+            } else {
+                store.repair()?
+            };
+
+            let verb = if args.dry_run {
+                "would remove"
+            } else {
+                "removed"
+            };
+            for (tag, item) in &report.forward {
+                println!("{} tag_items entry: tag {}, item {}", verb, tag, item);
+            }
+            for (item, tag) in &report.reverse {
+                println!("{} item_tags entry: item {}, tag {}", verb, item, tag);
+            }
+            println!(
+                "{} {} tag_items and {} item_tags entries",
+                verb,
+                report.forward.len(),
+                report.reverse.len()
+            );
+
+            Ok(Outcome::Success)
+        }
+        #[cfg(feature = "serve")]
+        Command::Serve(args) => {
+            serve::serve(store, args.addr)?;
+
+            Ok(Outcome::Success)
+        }
+        Command::Stats => {
+            let stats_start = Instant::now();
+            let tags = store.tag_count();
+            let items = store.item_count()?;
+            let associations = store.association_count();
+            let size_on_disk = store.size_on_disk()?;
+
+            println!("tags: {}", tags);
+            println!("items: {}", items);
+            println!("associations: {}", associations);
+            println!("size on disk: {} bytes", size_on_disk);
+            println!("gathered stats in {}ms", stats_start.elapsed().as_millis());
+
+            Ok(Outcome::Success)
+        }
+        Command::Export(args) => {
+            let stdout = std::io::stdout();
+            let out = std::io::BufWriter::new(stdout.lock());
+            if args.tag_index {
+                store.export_tag_index(out)?;
+            } else {
+                store.export_ndjson(out)?;
+            }
+
+            Ok(Outcome::Success)
+        }
+        Command::ImportNdjson(args) => {
+            let import_start = Instant::now();
+            let imported = if args.path == "-" {
+                let stdin = std::io::stdin();
+                store.import_ndjson(stdin.lock())?
+            } else {
+                store.import_ndjson(std::io::BufReader::new(fs::File::open(&args.path)?))?
+            };
+            println!(
+                "imported {} items in {}ms",
+                imported,
+                import_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::ImportCsv(args) => {
+            let import_start = Instant::now();
+            let imported = import_csv(&store, &args.path, &args.name_column)?;
+            println!(
+                "imported {} items in {}ms",
+                imported,
+                import_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::ImportDir(args) => {
+            let import_start = Instant::now();
+            let imported = import_dir(&store, Path::new(&args.root))?;
+            println!(
+                "imported {} files in {}ms",
+                imported,
+                import_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::Backup(args) => {
+            let backup_start = Instant::now();
+            store.backup(Path::new(&args.file))?;
+            println!(
+                "backed up to '{}' in {}ms",
+                args.file,
+                backup_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::Restore(_) => unreachable!("restore is handled before the store is opened"),
+        Command::Flush => {
+            let flush_start = Instant::now();
+            let flushed = store.flush()?;
+            println!(
+                "flushed {} bytes in {}ms",
+                flushed,
+                flush_start.elapsed().as_millis()
+            );
+
+            Ok(Outcome::Success)
+        }
+        Command::History => {
+            println!("history: past imports");
+
+            let mut count: usize = 0;
+            for record_result in store.import_history() {
+                let record = record_result?;
+                println!(
+                    "{} library:{} items:{} tags_created:{}",
+                    record.time, record.library_id, record.items, record.tags_created
+                );
+                count += 1;
+            }
+            println!("got {} imports", count);
+
+            Ok(Outcome::Success)
+        }
+        Command::StaleTags(args) => {
+            let cutoff = chrono::Utc::now() - args.older_than;
+            println!("stale-tags: tags unused since {}", cutoff);
+
+            let mut count: usize = 0;
+            for stale_result in store.stale_tags(cutoff) {
+                let (tag, last_used) = stale_result?;
+                match last_used {
+                    Some(time) => println!("{:10}:{} (last used {})", tag.id, tag.name, time),
+                    None => println!("{:10}:{} (never used)", tag.id, tag.name),
+                }
+                count += 1;
+            }
+            println!("got {} stale tags", count);
+
+            Ok(Outcome::Success)
+        }
+    }
+}
+
+/// What importing a track did to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrackChange {
+    Added,
+    Updated,
+    Unchanged,
+}
+
+/// Numeric tags derived from tracks, which are replaced whenever a track is imported again.
+const IMPORTED_NUMBERS: &[&str] = &["year", "rating", "plays", "last_played"];
+
+/// Item attribute listing, as a JSON array, the tags the last import gave an item. The next
+/// import removes the ones the track no longer has, leaving tags added by hand alone.
+const ATTR_IMPORTED_TAGS: &str = "imported_tags";
+
+/// Starts the name of the tag each track gets for every playlist it's in. Playlists are read
+/// after the tracks, so these tags are diffed separately, by `update_playlist_tags`.
+const PLAYLIST_TAG_PREFIX: &str = "playlist:";
+
+/// Counters seeded from track fields, which are removed when the field goes away.
+const IMPORTED_COUNTERS: &[&str] = &["loved", "skip_count", "play_count"];
+
+/// Add one track from an iTunes library to the store, or update the item it was imported as
+/// before, going by its persistent ID. `old_numbers` is every item's numeric tags from before
+/// the import began.
+fn index_track(
+    store: &Store,
+    track: &itunes::Track,
+    rename_map: &HashMap<String, String>,
+    skip_threshold: u32,
+    old_numbers: &HashMap<ItemID, Vec<(String, i64)>>,
+    pending: &mut Vec<(ItemID, String)>,
+) -> Result<(ItemID, TrackChange), Box<dyn Error>> {
+    let existing = store.item_by_persistent_id(&track.persistent_id)?;
+    let item: ItemID = match existing {
+        Some(item) => item,
+        None => {
+            let item = store.id()?.into();
+            store.set_persistent_id(item, &track.persistent_id)?;
+            item
+        }
+    };
+
+    // Tag some things about this entity
+    let mut tags: Vec<String> = Vec::new();
+    let mut numbers: Vec<(&str, i64)> = Vec::new();
+    if let Some(album) = &track.album {
+        tags.push(renamed(rename_map, album).to_string());
+    }
+    if let Some(artist) = &track.artist {
+        tags.push(renamed(rename_map, artist).to_string());
+    }
+    if let Some(composer) = &track.composer {
+        tags.push(renamed(rename_map, composer).to_string());
+    }
+    if let Some(genre) = &track.genre {
+        tags.push(renamed(rename_map, genre).to_string());
+    }
+    if let Some(year) = &track.year {
+        tags.push(renamed(rename_map, &format!("{}", year)).to_string());
+        numbers.push(("year", *year as i64));
+    }
+    if let Some(name) = &track.name {
+        tags.push(renamed(rename_map, name).to_string());
+        store.set_item_meta(item, name)?;
+    }
+
+    if let Some(location) = &track.location {
+        store.set_item_attr(item, store::ATTR_LOCATION, location)?;
+
+        let path = store::normalize_location(location);
+        if let Some(folder) = Path::new(&path).parent() {
+            tags.push(format!("folder:{}", folder.display()));
+        }
+    }
+
+    // Derive tags from listening data
+    let mut counters: Vec<(&str, i64)> = Vec::new();
+    if let Some(loved) = track.loved {
+        counters.push(("loved", loved.into()));
+        if loved {
+            tags.push("loved".to_string());
+            store.set_favorite(item, true)?;
+        }
+    }
+    if let Some(rating) = track.rating {
+        numbers.push(("rating", (rating / 20).into()));
+    }
+    if let Some(skip_count) = track.skip_count {
+        counters.push(("skip_count", skip_count.into()));
+        if skip_count > skip_threshold {
+            tags.push("often-skipped".to_string());
+        }
+    }
+
+    // Seed counters from the library, and make listening history searchable by range
+    if let Some(play_count) = track.play_count {
+        counters.push(("play_count", play_count.into()));
+        numbers.push(("plays", play_count.into()));
+    }
+    if let Some(play_date) = track.play_date {
+        numbers.push(("last_played", days_since_epoch(play_date)));
+    }
+
+    for &(key, value) in &counters {
+        store.set_item_counter(item, key, value)?;
+    }
+    if existing.is_some() {
+        for &key in IMPORTED_COUNTERS {
+            if !counters.iter().any(|&(set, _)| set == key) {
+                store.remove_item_counter(item, key)?;
+            }
+        }
+    }
+
+    // Replace the numbers from the last import
+    let mut previous_numbers: Vec<(&str, i64)> = Vec::new();
+    if let Some(old) = old_numbers.get(&item) {
+        for (key, value) in old {
+            if IMPORTED_NUMBERS.contains(&key.as_str()) {
+                store.untag_numeric(item, key, *value)?;
+                previous_numbers.push((key.as_str(), *value));
+            }
+        }
+    }
+    for &(key, value) in &numbers {
+        store.tag_numeric(item, key, value)?;
+    }
+
+    // Drop the tags from the last import that the track doesn't have any more. Playlist tags
+    // are left for `update_playlist_tags`, and carried over until then.
+    let previous_tags: Option<Vec<String>> = match store.get_item_attr(item, ATTR_IMPORTED_TAGS)? {
+        Some(json) => Some(serde_json::from_str(&json)?),
+        None => None,
+    };
+    let (previous_playlists, previous_tags): (Vec<String>, Option<Vec<String>>) =
+        match previous_tags {
+            Some(previous_tags) => {
+                let (playlists, others): (Vec<String>, Vec<String>) = previous_tags
+                    .into_iter()
+                    .partition(|name| name.starts_with(PLAYLIST_TAG_PREFIX));
+                (playlists, Some(others))
+            }
+            None => (Vec::new(), None),
+        };
+    let change = match (existing, previous_tags) {
+        (None, _) => TrackChange::Added,
+        (Some(_), Some(previous_tags)) => {
+            let current: HashSet<&String> = tags.iter().collect();
+            for name in previous_tags.iter().filter(|name| !current.contains(name)) {
+                store.untag_string(item, name)?;
+                if name == "loved" {
+                    store.set_favorite(item, false)?;
+                }
+            }
+
+            previous_numbers.sort();
+            numbers.sort();
+            if previous_tags.iter().collect::<HashSet<_>>() == current
+                && previous_numbers == numbers
+            {
+                TrackChange::Unchanged
+            } else {
+                TrackChange::Updated
+            }
+        }
+        (Some(_), None) => TrackChange::Updated,
+    };
+
+    let recorded: Vec<&String> = tags.iter().chain(previous_playlists.iter()).collect();
+    store.set_item_attr(item, ATTR_IMPORTED_TAGS, &serde_json::to_string(&recorded)?)?;
+    pending.extend(tags.into_iter().map(|tag| (item, tag)));
+    Ok((item, change))
+}
+
+/// Add a track that isn't one of the wanted kinds as an item tagged only `kind:<Kind>`. An
+/// excluded track is only meant to be found by kind, so if an earlier import gave it other
+/// tags, playlist ones included, or numbers and counters, those are removed. It isn't given
+/// playlist tags either. Without `--tag-excluded`, excluded tracks aren't touched at all.
+fn index_excluded_track(
+    store: &Store,
+    track: &itunes::Track,
+    old_numbers: &HashMap<ItemID, Vec<(String, i64)>>,
+    pending: &mut Vec<(ItemID, String)>,
+) -> Result<(), Box<dyn Error>> {
+    let existing = store.item_by_persistent_id(&track.persistent_id)?;
+    let item: ItemID = match existing {
+        Some(item) => item,
+        None => {
+            let item = store.id()?.into();
+            store.set_persistent_id(item, &track.persistent_id)?;
+            item
+        }
+    };
+    if let Some(name) = &track.name {
+        store.set_item_meta(item, name)?;
+    }
+
+    let kind = track.kind.as_ref().unwrap_or(&track.track_type);
+    let kind_tag = format!("kind:{}", kind);
+    if existing.is_some() {
+        let previous_tags: Vec<String> = match store.get_item_attr(item, ATTR_IMPORTED_TAGS)? {
+            Some(json) => serde_json::from_str(&json)?,
+            None => Vec::new(),
+        };
+        for name in previous_tags.iter().filter(|name| **name != kind_tag) {
+            store.untag_string(item, name)?;
+            if name == "loved" {
+                store.set_favorite(item, false)?;
+            }
+        }
+
+        if let Some(old) = old_numbers.get(&item) {
+            for (key, value) in old {
+                if IMPORTED_NUMBERS.contains(&key.as_str()) {
+                    store.untag_numeric(item, key, *value)?;
+                }
+            }
+        }
+        for &key in IMPORTED_COUNTERS {
+            store.remove_item_counter(item, key)?;
+        }
+    }
+
+    store.set_item_attr(
+        item,
+        ATTR_IMPORTED_TAGS,
+        &serde_json::to_string(&[&kind_tag])?,
+    )?;
+    pending.push((item, kind_tag));
+    Ok(())
+}
+
+/// Give `item` a tag for each of `playlists`, and take off the playlist tags the last import
+/// gave it that aren't among them. The tags are recorded with the rest of the imported tags.
+fn update_playlist_tags(
+    store: &Store,
+    item: ItemID,
+    playlists: BTreeSet<String>,
+    pending: &mut Vec<(ItemID, String)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut recorded: Vec<String> = match store.get_item_attr(item, ATTR_IMPORTED_TAGS)? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => Vec::new(),
+    };
+
+    let gone = recorded
+        .iter()
+        .filter(|name| name.starts_with(PLAYLIST_TAG_PREFIX) && !playlists.contains(*name));
+    for name in gone {
+        store.untag_string(item, name)?;
+    }
+
+    recorded.retain(|name| !name.starts_with(PLAYLIST_TAG_PREFIX));
+    recorded.extend(playlists.iter().cloned());
+    store.set_item_attr(item, ATTR_IMPORTED_TAGS, &serde_json::to_string(&recorded)?)?;
+    pending.extend(playlists.into_iter().map(|tag| (item, tag)));
+    Ok(())
+}
+
+/// Whether `track` is one of `kinds`: each is matched, ignoring case, against part of the
+/// track's Kind (e.g. "audio" in "MPEG audio file") or its whole Track Type (e.g. "URL").
+fn kind_matches(track: &itunes::Track, kinds: &[String]) -> bool {
+    let kind = track.kind.as_deref().unwrap_or("").to_lowercase();
+    kinds.iter().any(|wanted| {
+        let wanted = wanted.to_lowercase();
+        kind.contains(&wanted) || track.track_type.to_lowercase() == wanted
+    })
+}
+
+/// Whole days from the unix epoch to `time`, which is how dates are kept as numeric tags.
+fn days_since_epoch(time: chrono::DateTime<chrono::Utc>) -> i64 {
+    time.timestamp().div_euclid(24 * 60 * 60)
+}
+
+/// Import a CSV file as described on `ImportCsv`, returning how many rows were imported.
+fn import_csv(store: &Store, path: &str, name_column: &str) -> Result<usize, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let name_index = headers
+        .iter()
+        .position(|header| header == name_column)
+        .ok_or_else(|| format!("\"{}\" has no column named \"{}\"", path, name_column))?;
+
+    let mut pending: Vec<(ItemID, String)> = Vec::new();
+    let mut imported: usize = 0;
+    for record in reader.records() {
+        let record = record?;
+        let item: ItemID = store.id()?.into();
+
+        for (i, (column, cell)) in headers.iter().zip(record.iter()).enumerate() {
+            if cell.is_empty() {
+                continue;
+            }
+            if i == name_index {
+                store.set_item_meta(item, cell)?;
+            } else {
+                pending.push((item, format!("{}:{}", column, cell)));
+            }
+        }
+        imported += 1;
+
+        if pending.len() >= IMPORT_BATCH {
+            flush_tags(store, &mut pending)?;
+        }
+    }
+    flush_tags(store, &mut pending)?;
+
+    Ok(imported)
+}
+
+/// Import the files under `root` as described on `ImportDir`, returning how many were imported.
+/// Names that aren't valid UTF-8 are converted lossily, and the item is tagged "encoding:lossy".
+fn import_dir(store: &Store, root: &Path) -> Result<usize, Box<dyn Error>> {
+    let is_hidden = |entry: &walkdir::DirEntry| {
+        entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+    };
+
+    let mut pending: Vec<(ItemID, String)> = Vec::new();
+    let mut imported: usize = 0;
+    for entry in walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|e| !is_hidden(e))
+    {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let item: ItemID = store.id()?.into();
+        let path = entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or_else(|_| entry.path());
+        let mut lossy = false;
+        let mut lossy_str = |os: &std::ffi::OsStr| {
+            lossy |= os.to_str().is_none();
+            os.to_string_lossy().into_owned()
+        };
+
+        if let Some(ext) = path.extension() {
+            pending.push((item, format!("ext:{}", lossy_str(ext))));
+        }
+        if let Some(parent) = path.parent() {
+            for component in parent.iter() {
+                pending.push((item, format!("dir:{}", lossy_str(component))));
+            }
+        }
+        if let Some(stem) = path.file_stem() {
+            store.set_item_meta(item, &lossy_str(stem))?;
+        }
+        if lossy {
+            pending.push((item, "encoding:lossy".to_string()));
+        }
+        imported += 1;
+
+        if pending.len() >= IMPORT_BATCH {
+            flush_tags(store, &mut pending)?;
+        }
+    }
+    flush_tags(store, &mut pending)?;
+
+    Ok(imported)
+}
+
+/// Apply and clear a queue of (item, tag name) pairs.
+fn flush_tags(store: &Store, pending: &mut Vec<(ItemID, String)>) -> Result<(), Box<dyn Error>> {
+    let ops: Vec<(ItemID, &str)> = pending
+        .iter()
+        .map(|(item, name)| (*item, name.as_str()))
+        .collect();
+    store.tag_batch(&ops)?;
+    pending.clear();
+    Ok(())
+}
+
+/// The items named by an `--item` argument: one ID, or "-" for every ID on stdin.
+fn parse_item_arg(arg: &str) -> Result<Vec<ItemID>, Box<dyn Error>> {
+    if arg == "-" {
+        return read_item_ids(io::stdin().lock());
+    }
+    let item: ItemID = arg
+        .parse()
+        .map_err(|e| format!("\"{}\" isn't an item ID: {}", arg, e))?;
+    Ok(vec![item])
+}
+
+/// "item 7" for one item, or "3 items".
+fn describe_items(items: &[ItemID]) -> String {
+    match items {
+        [item] => format!("item {}", item),
+        items => format!("{} items", items.len()),
+    }
+}
+
+/// Read whitespace-separated item IDs, failing on the first one that isn't a number.
+fn read_item_ids<R: BufRead>(input: R) -> Result<Vec<ItemID>, Box<dyn Error>> {
+    let mut items = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        for word in line?.split_whitespace() {
+            let item: ItemID = word
+                .parse()
+                .map_err(|e| format!("stdin:{}: \"{}\" isn't an item ID: {}", i + 1, word, e))?;
+            items.push(item);
+        }
+    }
+    Ok(items)
+}
+
+/// Look up the IDs of the tags that exist, skipping the rest.
+fn resolve_tags(store: &Store, names: &[String]) -> Result<Vec<store::TagID>, Box<dyn Error>> {
+    let mut tags = Vec::new();
+    for name in names {
+        if let Some(tag) = store.get_tag_id(name)? {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// Load a tag rename map from a file of `from<TAB>to` lines. Blank lines are ignored.
+fn load_rename_map(path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+
+    let mut map = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '\t');
+        match (parts.next(), parts.next()) {
+            (Some(from), Some(to)) => {
+                map.insert(from.to_string(), to.to_string());
+            }
+            _ => return Err(format!("{}:{}: expected \"from<TAB>to\"", path, i + 1).into()),
+        }
+    }
+
+    Ok(map)
+}
+
+/// Rewrite a tag value through the rename map, passing it through if it isn't mapped.
+fn renamed<'a>(map: &'a HashMap<String, String>, value: &'a str) -> &'a str {
+    map.get(value).map(String::as_str).unwrap_or(value)
+}
+
+/// Parse an attribute filter like "location=file:///a.mp3". The value may contain `=`.
+fn parse_attr_filter(text: &str) -> Result<(String, String), String> {
+    let mut parts = text.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid filter \"{}\", expected key=value", text)),
+    }
+}
+
+/// Parse a numeric attribute filter like "play_count=3".
+fn parse_counter_filter(text: &str) -> Result<(String, i64), String> {
+    let (key, value) = parse_attr_filter(text)?;
+    let value: i64 = value
+        .parse()
+        .map_err(|_| format!("attribute value in \"{}\" must be a number", text))?;
+    Ok((key, value))
+}
+
+/// Parse a duration like "45s", "90m", "12h", "30d", or "2w".
+fn parse_duration(text: &str) -> Result<chrono::Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| text.len());
+    let (amount, unit) = text.split_at(split);
+
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration \"{}\"", text))?;
+
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        "w" => Ok(chrono::Duration::weeks(amount)),
+        _ => Err(format!(
+            "invalid duration unit in \"{}\", expected one of s, m, h, d, w",
+            text
+        )),
     }
 }
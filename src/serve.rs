@@ -0,0 +1,215 @@
+//! A read-only HTTP API over a store, for `tagmu serve`. Every response is JSON:
+//!
+//! - `GET /tags`: every tag, as `[{"id": 42, "name": "Bach"}, ...]`
+//! - `GET /tags/{name}/items`: IDs of the items tagged `name`, which is percent-decoded
+//! - `GET /items/{id}/tags`: the item's tags, like `/tags`
+//! - `POST /query`: items matching `{"all": [...], "any": [...], "none": [...]}`, with the same
+//!   rules as the `query` command
+//!
+//! Unknown tags and items get a 404. Only built with the `serve` feature.
+
+use crate::asynced::AsyncStore;
+use crate::store::{Error, ItemID, Store, Tag, TagID};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+type HandlerResult = Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Body of a `POST /query`. Each list holds tag names, and can be left out.
+#[derive(Deserialize)]
+struct QueryBody {
+    #[serde(default)]
+    all: Vec<String>,
+
+    #[serde(default)]
+    any: Vec<String>,
+
+    #[serde(default)]
+    none: Vec<String>,
+}
+
+/// Answer requests on `addr` until the process is killed. `store` should be opened read-only;
+/// nothing here writes to it.
+pub fn serve(store: Store, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let store = AsyncStore::new(store);
+    let mut runtime = tokio::runtime::Runtime::new()?;
+
+    runtime.block_on(async move {
+        let make_service = make_service_fn(move |_conn| {
+            let store = store.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let store = store.clone();
+                    async move { Ok::<_, Infallible>(respond(&store, request).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        eprintln!("listening on http://{}", addr);
+        server.await
+    })?;
+
+    Ok(())
+}
+
+async fn respond(store: &AsyncStore, request: Request<Body>) -> Response<Body> {
+    match route(store, request).await {
+        Ok(response) => response,
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+async fn route(store: &AsyncStore, request: Request<Body>) -> HandlerResult {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    match (method, path.as_str()) {
+        (Method::GET, "/tags") => {
+            let tags = store
+                .run(|store| store.all_tags().collect::<Result<Vec<Tag>, Error>>())
+                .await?;
+            json_response(&tags)
+        }
+        (Method::POST, "/query") => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            match serde_json::from_slice(&body) {
+                Ok(query) => query_items(store, query).await,
+                Err(err) => Ok(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+            }
+        }
+        (Method::GET, path) => {
+            if let Some(name) = between(path, "/tags/", "/items") {
+                tag_items(store, name).await
+            } else if let Some(id) = between(path, "/items/", "/tags") {
+                item_tags(store, id).await
+            } else {
+                Ok(error_response(StatusCode::NOT_FOUND, "no such endpoint"))
+            }
+        }
+        _ => Ok(error_response(StatusCode::NOT_FOUND, "no such endpoint")),
+    }
+}
+
+async fn tag_items(store: &AsyncStore, raw_name: &str) -> HandlerResult {
+    let name = match percent_decode_str(raw_name).decode_utf8() {
+        Ok(name) => name.into_owned(),
+        Err(_) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "tag name isn't valid UTF-8",
+            ))
+        }
+    };
+
+    let items = store
+        .run(move |store| match store.get_tag_id(&name)? {
+            Some(tag) => Ok(Some(
+                store
+                    .get_tag_item_ids(tag)
+                    .collect::<Result<Vec<ItemID>, Error>>()?,
+            )),
+            None => Ok(None),
+        })
+        .await?;
+
+    match items {
+        Some(items) => json_response(&items),
+        None => Ok(error_response(StatusCode::NOT_FOUND, "no such tag")),
+    }
+}
+
+async fn item_tags(store: &AsyncStore, raw_id: &str) -> HandlerResult {
+    let item: ItemID = match raw_id.parse() {
+        Ok(item) => item,
+        Err(_) => return Ok(error_response(StatusCode::BAD_REQUEST, "bad item ID")),
+    };
+
+    let tags = store
+        .run(move |store| {
+            if !store.item_exists(item)? {
+                return Ok(None);
+            }
+            Ok(Some(
+                store
+                    .get_item_tags(item)
+                    .collect::<Result<Vec<Tag>, Error>>()?,
+            ))
+        })
+        .await?;
+
+    match tags {
+        Some(tags) => json_response(&tags),
+        None => Ok(error_response(StatusCode::NOT_FOUND, "no such item")),
+    }
+}
+
+async fn query_items(store: &AsyncStore, query: QueryBody) -> HandlerResult {
+    if query.all.is_empty() && query.any.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "query needs at least one \"all\" or \"any\" tag",
+        ));
+    }
+
+    let items = store
+        .run(move |store| {
+            // Unknown tags can't be on any item
+            let resolve = |names: &[String]| -> Result<Vec<TagID>, Error> {
+                let mut tags = Vec::new();
+                for name in names {
+                    if let Some(tag) = store.get_tag_id(name)? {
+                        tags.push(tag);
+                    }
+                }
+                Ok(tags)
+            };
+            let all = resolve(&query.all)?;
+            let any = resolve(&query.any)?;
+            let none = resolve(&query.none)?;
+            if all.len() < query.all.len() || (!query.any.is_empty() && any.is_empty()) {
+                return Ok(Vec::new());
+            }
+
+            store
+                .query(&all, &any, &none)
+                .collect::<Result<Vec<ItemID>, Error>>()
+        })
+        .await?;
+
+    json_response(&items)
+}
+
+/// The part of `path` after `prefix` and before `suffix`, if it has both. The middle may hold
+/// more slashes, so hierarchical tag names work without escaping.
+fn between<'a>(path: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    if path.len() <= prefix.len() + suffix.len()
+        || !path.starts_with(prefix)
+        || !path.ends_with(suffix)
+    {
+        return None;
+    }
+    Some(&path[prefix.len()..path.len() - suffix.len()])
+}
+
+fn json_response<T: Serialize>(value: &T) -> HandlerResult {
+    let body = serde_json::to_vec(value)?;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "error": message }).to_string();
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        "Content-Type",
+        "application/json".parse().expect("valid header"),
+    );
+    response
+}
@@ -1,8 +1,21 @@
-use crate::id::Id;
+use crate::id::{Id, ID_LEN};
+use chrono::{DateTime, TimeZone, Utc};
+use lru::LruCache;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
 use sled;
-use sled::{IVec, TransactionError};
+use sled::{ConflictableTransactionResult, IVec, TransactionError, Transactional};
 use snafu::{Backtrace, ResultExt, Snafu};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+mod query;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -14,225 +27,3035 @@ pub enum Error {
     #[snafu(context(false))]
     FailedTransaction { source: TransactionError },
 
-    #[snafu(display("key \"{}\" not found", key))]
-    NotFound { key: u64 },
+    #[snafu(display("no tag with ID {}", id))]
+    TagNotFound { id: TagID },
+
+    #[snafu(display("no item with ID {}", id))]
+    ItemNotFound { id: ItemID },
 
     #[snafu(display("value \"{}\" not found", val))]
     ValueNotFound { val: String },
 
+    #[snafu(display(
+        "corrupt entry in {}: {} bytes where {} were expected",
+        tree,
+        len,
+        expected
+    ))]
+    CorruptKey {
+        len: usize,
+        expected: usize,
+        tree: &'static str,
+    },
+
+    #[snafu(display("tag {} has a name that isn't valid UTF-8: {:?}", id, bytes))]
+    InvalidTagName { id: TagID, bytes: Vec<u8> },
+
+    #[snafu(display("tag name \"{}\" is already in use", name))]
+    NameInUse { name: String },
+
+    #[snafu(display("couldn't encode record: {}", source))]
+    #[snafu(context(false))]
+    Encoding { source: serde_json::Error },
+
+    #[snafu(display("bad record on line {}: {}", line, source))]
+    BadRecord {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("I/O error: {}", source))]
+    #[snafu(context(false))]
+    Io { source: std::io::Error },
+
+    #[snafu(display("won't restore over \"{}\", it already has data in it", path))]
+    NotEmpty { path: String },
+
+    #[snafu(display("not a tagmu backup, or it's been truncated"))]
+    BadArchive {},
+
+    #[snafu(display("can't back up a {}-byte entry; entries must be under 2 GiB", len))]
+    ChunkTooLarge { len: usize },
+
+    #[snafu(display("couldn't recover the store at \"{}\", it may be corrupt", path))]
+    RecoveryFailed { path: String },
+
+    #[snafu(display("this store doesn't maintain the item -> tags index"))]
+    ReverseIndexDisabled {},
+
+    #[snafu(display("the store was opened read-only"))]
+    ReadOnly {},
+
+    #[snafu(display(
+        "store has schema version {}, but this build uses version {}",
+        found,
+        expected
+    ))]
+    SchemaMismatch { found: u32, expected: u32 },
+
     #[snafu(display("Internal error"))]
     InternalError {},
 }
 
+/// What `Store::rename_prefix` does when a renamed tag's new name is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixCollision {
+    /// Fail before renaming anything
+    Error,
+
+    /// Merge the renamed tag into the existing one
+    Merge,
+}
+
+/// Tunables for opening a `Store`. The defaults match `Store::open`.
+#[derive(Debug, Clone)]
+pub struct StoreOptions {
+    /// Number of tag name -> TagID resolutions to keep in an in-memory LRU. `None` or `Some(0)`
+    /// disables it.
+    pub name_cache_entries: Option<usize>,
+
+    /// Bytes of sled's page cache. More speeds up big imports; less suits small stores.
+    pub cache_capacity: u64,
+
+    /// How often sled flushes buffered writes to disk, in milliseconds. `None` keeps sled's
+    /// default. Writes since the last flush are lost if the process dies, so raise this only for
+    /// bulk imports that can be re-run.
+    pub flush_every_ms: Option<u64>,
+
+    /// Whether to keep the item -> tags index. Without it, tagging is about twice as cheap but
+    /// items can't be asked for their tags. This is recorded when the store is created, and the
+    /// recorded setting is used from then on.
+    pub maintain_reverse_index: bool,
+
+    /// Refuse every write with `Error::ReadOnly`. See `Store::open_read_only`.
+    pub read_only: bool,
+
+    /// Resolve tag names without regard to case, so "ROCK" and "rock" are the same tag. Tags
+    /// keep the name they were created with for display. Like `maintain_reverse_index`, this is
+    /// recorded when the store is created and the recorded setting wins. A store that already
+    /// has tags from before the setting was recorded stays case-sensitive, since its names are
+    /// indexed with their case.
+    pub case_insensitive: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        StoreOptions {
+            name_cache_entries: None,
+            cache_capacity: 10 * 1000 * 1000, // 10 MiB
+            flush_every_ms: None,
+            maintain_reverse_index: true,
+            read_only: false,
+            case_insensitive: false,
+        }
+    }
+}
+
+/// A tag store. No method needs `&mut self`: sled trees synchronize internally, so a `Store` can
+/// be shared between threads behind an `Arc`.
 pub struct Store {
     sled: sled::Db,
 
+    // Where the store lives on disk, unless it's temporary
+    path: Option<PathBuf>,
+
+    // Store-wide bookkeeping
+    meta: sled::Tree,
+
+    // Whether `item_tags` is kept up to date
+    reverse_index: bool,
+
+    // Whether writes are refused
+    read_only: bool,
+
+    // Whether `tag_name_ids` is keyed by lowercased name
+    case_insensitive: bool,
+
+    // Added to every generated ID. A restored store sets this above every ID in the backup,
+    // since sled's ID generator isn't part of it.
+    id_floor: u64,
+
+    // Recently resolved tag names, if enabled
+    name_cache: Option<Mutex<LruCache<String, TagID>>>,
+
     // Information governing tags
     tag_id_names: sled::Tree,
     tag_name_ids: sled::Tree,
 
-    // Forward and reverse tag indices
+    // Number of items with each tag, for the tags that have been counted
+    tag_counts: sled::Tree,
+
+    // Per-tag metadata, keyed by (tag, field name)
+    tag_meta: sled::Tree,
+
+    // Per-item attributes, keyed by (item, attribute name)
+    item_attrs: sled::Tree,
+
+    // Tag implication rules, keyed by (tag, implied tag)
+    implications: sled::Tree,
+
+    // One record per import, keyed by a sequence number
+    import_log: sled::Tree,
+
+    // Favorited items, kept out of the tag vocabulary for a cheap lookup
+    favorites: sled::Tree,
+
+    // Display metadata for items, keyed by item. Currently just a name.
+    item_meta: sled::Tree,
+
+    // Numeric tags, keyed by (namespace, value, item) so a range of values is a range of keys
+    numeric_tags: sled::Tree,
+
+    // Items by the persistent ID of the track they were imported from. Each item also carries
+    // its persistent ID as the ATTR_PERSISTENT_ID attribute, so the entry can be found again.
+    persistent_ids: sled::Tree,
+
+    // Forward and reverse tag indices. These are keyed by ID only, never by name.
     tag_items: sled::Tree,
     item_tags: sled::Tree,
 }
 
+// Keys in `meta`
+const META_OPEN: &[u8] = b"open";
+const META_REVERSE_INDEX: &[u8] = b"reverse_index";
+const META_CASE_INSENSITIVE: &[u8] = b"case_insensitive";
+const META_ID_FLOOR: &[u8] = b"id_floor";
+const META_SCHEMA_VERSION: &[u8] = b"schema_version";
+
+// Start of every backup archive, so a wrong file is rejected up front
+const BACKUP_MAGIC: &[u8] = b"tagmu-backup\x01";
+
+// Chunk length marking the end of a list in a backup archive
+const END_OF_LIST: u32 = 1 << 31;
+
+/// Item attribute holding the item's file location, as imported.
+pub const ATTR_LOCATION: &str = "location";
+
+/// Item attribute holding the persistent ID of the iTunes track the item was imported from.
+pub const ATTR_PERSISTENT_ID: &str = "persistent_id";
+
+/// Version of the on-disk layout written by this code.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Associations checked by the consistency sample in `health`
+const HEALTH_SAMPLE: usize = 1000;
+
+// Items rewritten per transaction by `compact_item_ids`
+const COMPACT_CHUNK: usize = 1000;
+
+// Bytes in a key made of two IDs, like the (tag, item) keys of `tag_items`
+const COMPOUND_LEN: usize = 2 * ID_LEN;
+
+// Bytes in a numeric tag's key after the namespace: a NUL, the value, and the item
+const NUMERIC_SUFFIX_LEN: usize = 1 + 8 + ID_LEN;
+
+// Value stored in the tag indices, where only the key matters
+const PRESENT: &[u8] = &[];
+
+// Fields in `tag_meta`
+const TAG_META_LAST_USED: &[u8] = b"last_used";
+const TAG_META_CREATED: &[u8] = b"created";
+const TAG_META_DESCRIPTION: &[u8] = b"description";
+// Followed by the alias itself, with an empty value
+const TAG_META_ALIAS: &[u8] = b"alias:";
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 impl Store {
     pub fn open(path: String) -> Result<Store> {
-        let config = sled::Config::new().path(path);
-        Self::from_sled(config)
+        Self::open_with(path, StoreOptions::default())
+    }
+
+    pub fn open_with(path: String, options: StoreOptions) -> Result<Store> {
+        let config = sled::Config::new().path(&path);
+        Self::from_sled(config, Some(PathBuf::from(&path)), options).map_err(|e| match e {
+            Error::StorageError {
+                source: sled::Error::Corruption { .. },
+            } => Error::RecoveryFailed { path },
+            e => e,
+        })
+    }
+
+    /// Open a store that can only be read from. Every method that would write to it fails with
+    /// `Error::ReadOnly` instead: tagging and untagging, creating, renaming, merging or removing
+    /// tags, removing or renumbering items, setting attributes, counters, metadata or favorites,
+    /// implications, and recording imports. Reads don't fill in the cached tag counts, either.
+    ///
+    /// sled locks the store's files while it's open, so this doesn't let a second process read a
+    /// store that another one is writing to.
+    pub fn open_read_only(path: String) -> Result<Store> {
+        let options = StoreOptions {
+            read_only: true,
+            ..StoreOptions::default()
+        };
+        Self::open_with(path, options)
     }
 
+    /// Open a new, empty store that's deleted when it's dropped. sled still backs it with files
+    /// in a temporary directory, which it removes on drop, so nothing survives the process and
+    /// two temporary stores never share data. To keep its contents, `backup` it first.
     pub fn open_temporary() -> Result<Store> {
         let config = sled::Config::new().temporary(true);
-        Self::from_sled(config)
+        Self::from_sled(config, None, StoreOptions::default())
+    }
+
+    /// Like `open_temporary`, but with a small cache, for embedding in tests or short-lived
+    /// tools where many stores may be open at once.
+    pub fn open_in_memory() -> Result<Store> {
+        let config = sled::Config::new().temporary(true);
+        let options = StoreOptions {
+            cache_capacity: 1000 * 1000, // 1 MiB
+            ..StoreOptions::default()
+        };
+        Self::from_sled(config, None, options)
+    }
+
+    /// Open the store at `path` and bring its layout up to `SCHEMA_VERSION`, one version at a
+    /// time, returning the upgraded store. A store that's already current is opened as usual.
+    /// This is needed when opening fails with `Error::SchemaMismatch` for an older store.
+    pub fn migrate(path: String, options: StoreOptions) -> Result<Store> {
+        let config = sled::Config::new().path(&path);
+        let store = Self::open_sled(config, Some(PathBuf::from(&path)), options)?;
+        store.writable()?;
+
+        let mut version = match store.check_schema() {
+            Ok(()) => return Ok(store),
+            Err(Error::SchemaMismatch { found, .. }) if found < SCHEMA_VERSION => found,
+            Err(e) => return Err(e),
+        };
+        while version < SCHEMA_VERSION {
+            match version {
+                // Nothing to do yet. When the layout changes, bump SCHEMA_VERSION and move the
+                // data from the old layout to the new one here.
+                1 => {}
+                _ => {
+                    return Err(Error::SchemaMismatch {
+                        found: version,
+                        expected: SCHEMA_VERSION,
+                    })
+                }
+            }
+            version += 1;
+            store
+                .meta
+                .insert(META_SCHEMA_VERSION, &version.to_be_bytes()[..])?;
+        }
+
+        Ok(store)
+    }
+
+    fn from_sled(
+        config: sled::Config,
+        path: Option<PathBuf>,
+        options: StoreOptions,
+    ) -> Result<Store> {
+        let store = Self::open_sled(config, path, options)?;
+        store.check_schema()?;
+        Ok(store)
     }
 
-    fn from_sled(config: sled::Config) -> Result<Store> {
-        let sled = config
-            .cache_capacity(10 * 1000 * 1000 /* 10 MiB */)
-            .open()?;
+    /// Open the store without checking its schema version.
+    fn open_sled(
+        config: sled::Config,
+        path: Option<PathBuf>,
+        options: StoreOptions,
+    ) -> Result<Store> {
+        let mut config = config.cache_capacity(options.cache_capacity);
+        if let Some(flush_every_ms) = options.flush_every_ms {
+            config = config.flush_every_ms(Some(flush_every_ms));
+        }
+        let sled = config.open()?;
+
+        // A leftover open marker means the last process to use the store never closed it, so
+        // sled may have discarded writes that weren't flushed before it went away.
+        let meta = sled.open_tree("meta")?;
+        if !options.read_only && meta.insert(META_OPEN, PRESENT)?.is_some() {
+            eprintln!(
+                "warning: store wasn't closed cleanly, so recent unflushed writes may be missing"
+            );
+        }
+
+        // Use the recorded reverse index setting, or record the requested one for a new store
+        let reverse_index = match meta.get(META_REVERSE_INDEX)? {
+            Some(flag) => flag[..] != [0u8],
+            None if options.read_only => options.maintain_reverse_index,
+            None => {
+                let flag: u8 = options.maintain_reverse_index.into();
+                meta.insert(META_REVERSE_INDEX, vec![flag])?;
+                options.maintain_reverse_index
+            }
+        };
+
+        let id_floor = match meta.get(META_ID_FLOOR)? {
+            Some(floor) => u64::from_be_bytes(must_u8_8(&floor, "meta")?),
+            None => 0,
+        };
 
         // Open the tag indices
         let tag_id_names = sled.open_tree("tag_id_names")?;
         let tag_name_ids = sled.open_tree("tag_name_ids")?;
+        let tag_meta = sled.open_tree("tag_meta")?;
+        let tag_counts = sled.open_tree("tag_counts")?;
+        let implications = sled.open_tree("implications")?;
+
+        // Like the reverse index setting, but a store that already has tags keeps its
+        // mixed-case names, so it stays case-sensitive whatever was asked for
+        let case_insensitive = match meta.get(META_CASE_INSENSITIVE)? {
+            Some(flag) => flag[..] != [0u8],
+            None => {
+                let case_insensitive = options.case_insensitive && tag_name_ids.is_empty();
+                if !options.read_only {
+                    let flag: u8 = case_insensitive.into();
+                    meta.insert(META_CASE_INSENSITIVE, vec![flag])?;
+                }
+                case_insensitive
+            }
+        };
 
         // Open the forward and reverse indices
         let tag_items = sled.open_tree("tag_items")?;
         let item_tags = sled.open_tree("item_tags")?;
 
+        // Open the item attributes
+        let item_attrs = sled.open_tree("item_attrs")?;
+        let favorites = sled.open_tree("favorites")?;
+        let numeric_tags = sled.open_tree("numeric_tags")?;
+        let item_meta = sled.open_tree("item_meta")?;
+        let persistent_ids = sled.open_tree("persistent_ids")?;
+
+        // Open the import log
+        let import_log = sled.open_tree("import_log")?;
+
+        // lru can't hold zero entries, so asking for none means no cache at all
+        let name_cache = options
+            .name_cache_entries
+            .filter(|&entries| entries > 0)
+            .map(|entries| Mutex::new(LruCache::new(entries)));
+
         Ok(Store {
             sled,
+            path,
+            meta,
+            reverse_index,
+            read_only: options.read_only,
+            case_insensitive,
+            id_floor,
+            name_cache,
             tag_items,
             item_tags,
             tag_id_names,
             tag_name_ids,
+            tag_meta,
+            tag_counts,
+            implications,
+            item_attrs,
+            favorites,
+            persistent_ids,
+            numeric_tags,
+            item_meta,
+            import_log,
         })
     }
 
+    /// Fail with `Error::SchemaMismatch` unless the store's layout is the one this code uses. A
+    /// store without a recorded version gets one: the current version if it's new, or 1 if it
+    /// was made before versions were recorded.
+    fn check_schema(&self) -> Result<()> {
+        let found = match self.meta.get(META_SCHEMA_VERSION)? {
+            Some(version) => {
+                let bytes: [u8; 4] = version[..].try_into().map_err(|_| Error::CorruptKey {
+                    len: version.len(),
+                    expected: 4,
+                    tree: "meta",
+                })?;
+                u32::from_be_bytes(bytes)
+            }
+            None => {
+                let version = if self.is_empty()? { SCHEMA_VERSION } else { 1 };
+                if !self.read_only {
+                    self.meta
+                        .insert(META_SCHEMA_VERSION, &version.to_be_bytes()[..])?;
+                }
+                version
+            }
+        };
+
+        if found != SCHEMA_VERSION {
+            return Err(Error::SchemaMismatch {
+                found,
+                expected: SCHEMA_VERSION,
+            });
+        }
+        Ok(())
+    }
+
+    /// Fail with `Error::ReadOnly` if the store was opened read-only.
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly {});
+        }
+        Ok(())
+    }
+
+    /// A new ID, for a tag, an item, or anything else keyed by ID.
+    ///
+    /// IDs from one store are unique and strictly increasing, including across closing and
+    /// reopening it, but not contiguous. sled persists its counter periodically and, after a
+    /// crash, resumes well past the last value it persisted. A restored backup raises `id_floor`
+    /// past every ID in the backup, so the same holds there. Temporary stores start over each
+    /// time.
     pub fn id(&self) -> Result<u64> {
-        Ok(self.sled.generate_id()?)
+        Ok(self.sled.generate_id()? + self.id_floor)
+    }
+
+    /// Write any buffered changes to disk, returning how many bytes were written. Once this
+    /// returns, everything written before it survives a crash.
+    pub fn flush(&self) -> Result<usize> {
+        Ok(self.sled.flush()?)
     }
 
-    pub fn tag_string(&mut self, item: ItemID, tag_name: &str) -> Result<()> {
-        // Look up the right tag
-        let found_tag: Option<IVec> = self.tag_name_ids.get(tag_name.as_bytes())?;
+    /// Write a copy of every tree in the store to `dest`, to be read back by `restore`. The
+    /// store is flushed first, but the copy is only consistent if nothing writes to the store
+    /// while it's being made.
+    ///
+    /// sled's own `export` and `import` only hand trees between two open databases in one
+    /// process, as iterators, so they'd still need a file format around them. This writes its
+    /// own: the magic, the ID generator's next value, then each tree as length-prefixed chunks.
+    /// Fails with `Error::ChunkTooLarge` if a key or value is 2 GiB or more.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        self.flush()?;
 
-        let tag: TagID;
-        if let Some(tag_vec) = found_tag {
-            tag = TagID::from(must_u8_8(&tag_vec)?);
-        } else {
-            // If the tag doesn't exist, create it.
-            tag = TagID::from(self.id()?);
-            self.update_tag(tag, tag_name)?;
+        let mut out = BufWriter::new(File::create(dest)?);
+        out.write_all(BACKUP_MAGIC)?;
+        out.write_all(&self.id()?.to_be_bytes())?;
+
+        for name in self.sled.tree_names() {
+            write_chunk(&mut out, Some(&name[..]))?;
+            for el in self.sled.open_tree(&name)?.iter() {
+                let (key, value) = el?;
+                write_chunk(&mut out, Some(&key[..]))?;
+                write_chunk(&mut out, Some(&value[..]))?;
+            }
+            write_chunk(&mut out, None)?;
+        }
+        write_chunk(&mut out, None)?;
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Restore a backup made by `backup` into the store at `dest_path`, and open it. Fails with
+    /// `Error::NotEmpty` if that store already has data, unless `force` is set, in which case
+    /// the data is replaced.
+    pub fn restore(src: &Path, dest_path: String, force: bool) -> Result<Store> {
+        let mut input = BufReader::new(File::open(src)?);
+        let mut magic = vec![0u8; BACKUP_MAGIC.len()];
+        input
+            .read_exact(&mut magic)
+            .map_err(|_| Error::BadArchive {})?;
+        if magic != BACKUP_MAGIC {
+            return Err(Error::BadArchive {});
+        }
+        let mut high_water = [0u8; 8];
+        input
+            .read_exact(&mut high_water)
+            .map_err(|_| Error::BadArchive {})?;
+
+        {
+            let store = Store::open(dest_path.clone())?;
+            if !force && !store.is_empty()? {
+                return Err(Error::NotEmpty { path: dest_path });
+            }
+            for name in store.sled.tree_names() {
+                store.sled.open_tree(&name)?.clear()?;
+            }
+
+            while let Some(name) = read_chunk(&mut input)? {
+                let tree = store.sled.open_tree(&name)?;
+                while let Some(key) = read_chunk(&mut input)? {
+                    let value = read_chunk(&mut input)?.ok_or(Error::BadArchive {})?;
+                    tree.insert(key, value)?;
+                }
+            }
+
+            // IDs handed out from now on have to be above any ID in the backup
+            store.meta.insert(META_ID_FLOOR, &high_water)?;
         }
 
+        Store::open(dest_path)
+    }
+
+    /// Whether the store has no data in it, apart from its own bookkeeping.
+    fn is_empty(&self) -> Result<bool> {
+        for name in self.sled.tree_names() {
+            if &name[..] == b"meta" {
+                continue;
+            }
+            if !self.sled.open_tree(&name)?.is_empty() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn tag_string(&self, item: ItemID, tag_name: &str) -> Result<()> {
+        let (tag, _) = self.get_or_create_tag(tag_name)?;
+
         // Tag the item with it
         self.tag(item, tag)
     }
 
+    /// Tag `item` with the key-value tag `key=value`, creating it if it doesn't exist yet.
+    pub fn tag_kv(&self, item: ItemID, key: &str, value: &str) -> Result<()> {
+        self.tag_string(item, &kv_tag_name(key, value))
+    }
+
+    /// Tag `item` with a number under the namespace `key`, e.g. ("year", 1969), so it can be
+    /// found by `range`. An item can carry several values under one namespace.
+    pub fn tag_numeric(&self, item: ItemID, key: &str, value: i64) -> Result<()> {
+        self.writable()?;
+        self.numeric_tags
+            .insert(numeric_key(key, value, item), PRESENT)?;
+        Ok(())
+    }
+
+    /// Remove the number `value` under `key` from `item`. Returns whether it was there.
+    pub fn untag_numeric(&self, item: ItemID, key: &str, value: i64) -> Result<bool> {
+        self.writable()?;
+        Ok(self
+            .numeric_tags
+            .remove(numeric_key(key, value, item))?
+            .is_some())
+    }
+
+    /// Every item's numeric tags, as (namespace, value) pairs. This reads the whole index, since
+    /// it's ordered by value rather than by item.
+    pub fn numeric_tags_by_item(&self) -> Result<HashMap<ItemID, Vec<(String, i64)>>> {
+        let mut by_item: HashMap<ItemID, Vec<(String, i64)>> = HashMap::new();
+        for (item, keys) in self.numeric_keys_by_item()? {
+            let values = by_item.entry(item).or_default();
+            for key in keys {
+                // The namespace, a NUL, then the value and the item, 8 bytes each
+                if key.len() < NUMERIC_SUFFIX_LEN {
+                    return Err(Error::CorruptKey {
+                        len: key.len(),
+                        expected: NUMERIC_SUFFIX_LEN,
+                        tree: "numeric_tags",
+                    });
+                }
+                let split = key.len() - NUMERIC_SUFFIX_LEN;
+                let namespace = String::from_utf8_lossy(&key[..split]).into_owned();
+                let value = decode_number(must_u8_8(&key[split + 1..split + 9], "numeric_tags")?);
+                values.push((namespace, value));
+            }
+        }
+        Ok(by_item)
+    }
+
+    /// Items with a numeric tag under `key` between `lo` and `hi` inclusive, ordered by value.
+    pub fn range(&self, key: &str, lo: i64, hi: i64) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        let start = numeric_key(key, lo, ItemID::from(0));
+        let end = numeric_key(key, hi, ItemID::from(u64::MAX));
+
+        self.numeric_tags
+            .range(start..=end)
+            .keys()
+            .map(|key_result| -> Result<ItemID> {
+                let key_vec = key_result?;
+                Ok(ItemID::from(must_u8_8(
+                    &key_vec[key_vec.len().saturating_sub(ID_LEN)..],
+                    "numeric_tags",
+                )?))
+            })
+    }
+
+    /// Remove the tag named `tag_name` from `item`. Returns `false` if no tag has that name.
+    pub fn untag_string(&self, item: ItemID, tag_name: &str) -> Result<bool> {
+        match self.get_tag_id(tag_name)? {
+            Some(tag) => {
+                self.untag(item, tag)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Resolve a tag name to its ID, creating the tag if it doesn't exist yet.
+    ///
+    /// Tag names are only ever stored in `tag_id_names` and `tag_name_ids`. The hot indices,
+    /// `tag_items` and `item_tags`, hold nothing but fixed-width ID pairs, so a name is interned
+    /// once here and never written again no matter how many items carry it.
+    pub fn intern_tag(&self, tag_name: &str) -> Result<TagID> {
+        let (tag, _) = self.get_or_create_tag(tag_name)?;
+        Ok(tag)
+    }
+
+    /// Like `intern_tag`, but also reports whether the tag had to be created.
+    pub fn get_or_create_tag(&self, tag_name: &str) -> Result<(TagID, bool)> {
+        if let Some(tag) = self.get_tag_id(tag_name)? {
+            return Ok((tag, false));
+        }
+
+        // If the tag doesn't exist, create it. Another thread may be creating the same tag, so
+        // claim the name first and use whichever ID got there.
+        self.writable()?;
+        let tag = TagID::from(self.id()?);
+        let claimed = self.tag_name_ids.compare_and_swap(
+            self.name_key(tag_name.as_bytes()),
+            None as Option<&[u8]>,
+            Some(&tag.to_bytes()[..]),
+        )?;
+        if let Err(lost) = claimed {
+            if let Some(existing) = lost.current {
+                let existing = TagID::from(must_u8_8(&existing, "tag_name_ids")?);
+                self.cache_tag_id(tag_name, existing);
+                return Ok((existing, false));
+            }
+        }
+
+        self.update_tag(tag, tag_name)?;
+        let now = Utc::now().timestamp_millis();
+        self.tag_meta
+            .insert(tag_meta_key(tag, TAG_META_CREATED), &now.to_be_bytes()[..])?;
+        Ok((tag, true))
+    }
+
+    /// Whether a tag named `tag_name` exists. Cheaper than `get_tag_id`, since the ID isn't
+    /// decoded.
+    pub fn tag_exists(&self, tag_name: &str) -> Result<bool> {
+        if self.cached_tag_id(tag_name).is_some() {
+            return Ok(true);
+        }
+        Ok(self
+            .tag_name_ids
+            .contains_key(self.name_key(tag_name.as_bytes()))?)
+    }
+
+    /// Whether a tag with this ID exists.
+    pub fn tag_id_exists(&self, id: TagID) -> Result<bool> {
+        Ok(self.tag_id_names.contains_key(id.to_bytes())?)
+    }
+
     pub fn get_tag_id(&self, tag_name: &str) -> Result<Option<TagID>> {
-        let found_tag: Option<IVec> = self.tag_name_ids.get(tag_name.as_bytes())?;
+        if let Some(tag) = self.cached_tag_id(tag_name) {
+            return Ok(Some(tag));
+        }
+
+        let found_tag: Option<IVec> = self.tag_name_ids.get(self.name_key(tag_name.as_bytes()))?;
 
         match found_tag {
             None => Ok(None),
-            Some(vec) => Ok(Some(TagID::from(must_u8_8(&vec)?))),
+            Some(vec) => {
+                let tag = TagID::from(must_u8_8(&vec, "tag_name_ids")?);
+                self.cache_tag_id(tag_name, tag);
+                Ok(Some(tag))
+            }
+        }
+    }
+
+    /// The `tag_name_ids` key for a tag name: the name itself, or its lowercase form if the store
+    /// is case-insensitive. Names that aren't UTF-8 are used as-is.
+    fn name_key(&self, name: &[u8]) -> Vec<u8> {
+        match std::str::from_utf8(name) {
+            Ok(name) if self.case_insensitive => name.to_lowercase().into_bytes(),
+            _ => name.to_vec(),
+        }
+    }
+
+    fn cache_key(&self, tag_name: &str) -> String {
+        if self.case_insensitive {
+            tag_name.to_lowercase()
+        } else {
+            tag_name.to_string()
         }
     }
 
-    pub fn tag(&mut self, item: ItemID, tag: TagID) -> Result<()> {
-        self.tag_items.insert(compound_key(tag, item), &[])?;
-        self.item_tags.insert(compound_key(item, tag), &[])?;
+    fn cached_tag_id(&self, tag_name: &str) -> Option<TagID> {
+        let mut cache = self.name_cache.as_ref()?.lock().ok()?;
+        cache.get(&self.cache_key(tag_name)).copied()
+    }
 
-        Ok(())
+    fn cache_tag_id(&self, tag_name: &str, tag: TagID) {
+        if let Some(Ok(mut cache)) = self.name_cache.as_ref().map(Mutex::lock) {
+            cache.put(self.cache_key(tag_name), tag);
+        }
+    }
+
+    fn uncache_tag_name(&self, tag_name: &str) {
+        if let Some(Ok(mut cache)) = self.name_cache.as_ref().map(Mutex::lock) {
+            cache.pop(&self.cache_key(tag_name));
+        }
     }
 
-    pub fn untag(&mut self, item: ItemID, tag: TagID) -> Result<()> {
-        self.tag_items.remove(compound_key(tag, item))?;
-        self.item_tags.remove(compound_key(item, tag))?;
+    /// Tag an item, along with every tag implied by `tag`. Both indices are updated in one
+    /// transaction.
+    pub fn tag(&self, item: ItemID, tag: TagID) -> Result<()> {
+        self.writable()?;
+        let tags = self.implied_tags(tag)?;
+        let reverse_index = self.reverse_index;
+
+        let added = (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<Vec<TagID>> {
+                let mut added = Vec::new();
+                for &implied in &tags {
+                    let old = tag_items.insert(&compound_key(implied, item)[..], PRESENT)?;
+                    if reverse_index {
+                        item_tags.insert(&compound_key(item, implied)[..], PRESENT)?;
+                    }
+                    if old.is_none() {
+                        added.push(implied);
+                    }
+                }
+                Ok(added)
+            },
+        )?;
+
+        for &implied in &added {
+            self.adjust_tag_count(implied, 1)?;
+        }
+        for &implied in &tags {
+            self.touch_tag(implied)?;
+        }
 
         Ok(())
     }
 
-    pub fn update_tag(&mut self, id: TagID, name: &str) -> Result<Tag> {
-        let tag = Tag {
-            id,
-            name: name.into(),
-        };
+    /// Apply one tag (and the tags it implies) to many items in a single transaction, returning
+    /// how many items didn't already have `tag`.
+    pub fn tag_items(&self, items: &[ItemID], tag: TagID) -> Result<usize> {
+        self.writable()?;
+        let tags = self.implied_tags(tag)?;
+        let reverse_index = self.reverse_index;
 
-        self.tag_id_names.insert(&id.to_bytes(), name.as_bytes())?;
-        self.tag_name_ids.insert(name.as_bytes(), &id.to_bytes())?;
+        let added = (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<usize> {
+                let mut added: usize = 0;
+                for &item in items {
+                    for &implied in &tags {
+                        let old = tag_items.insert(&compound_key(implied, item)[..], PRESENT)?;
+                        if reverse_index {
+                            item_tags.insert(&compound_key(item, implied)[..], PRESENT)?;
+                        }
 
-        Ok(tag)
+                        if implied == tag && old.is_none() {
+                            added += 1;
+                        }
+                    }
+                }
+                Ok(added)
+            },
+        )?;
+
+        if !items.is_empty() {
+            for &implied in &tags {
+                self.touch_tag(implied)?;
+                self.invalidate_tag_count(implied)?;
+            }
+        }
+
+        Ok(added)
     }
 
-    pub fn remove_tag(&mut self, id: TagID) -> Result<()> {
-        let removed: Option<IVec> = self.tag_id_names.remove(&id.to_bytes())?;
+    /// Tag one item with every tag in `tag_names` (and the tags they imply), creating tags as
+    /// needed, and return their IDs in the same order. Tags are created first; the item is then
+    /// tagged in one transaction, so it ends up with either all of them or none.
+    pub fn tag_all(&self, item: ItemID, tag_names: &[&str]) -> Result<Vec<TagID>> {
+        self.writable()?;
+        let mut ids = Vec::with_capacity(tag_names.len());
+        for name in tag_names {
+            ids.push(self.get_or_create_tag(name)?.0);
+        }
 
-        let old_name: IVec = match removed {
-            Some(name) => name,
-            None => return Err(Error::NotFound { key: id.into() }),
-        };
+        let mut seen: HashSet<TagID> = HashSet::new();
+        let mut tags: Vec<TagID> = Vec::new();
+        for &id in &ids {
+            for implied in self.implied_tags(id)? {
+                if seen.insert(implied) {
+                    tags.push(implied);
+                }
+            }
+        }
+        let reverse_index = self.reverse_index;
+
+        let added = (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<Vec<TagID>> {
+                let mut added = Vec::new();
+                for &tag in &tags {
+                    let old = tag_items.insert(&compound_key(tag, item)[..], PRESENT)?;
+                    if reverse_index {
+                        item_tags.insert(&compound_key(item, tag)[..], PRESENT)?;
+                    }
+                    if old.is_none() {
+                        added.push(tag);
+                    }
+                }
+                Ok(added)
+            },
+        )?;
+
+        for &tag in &added {
+            self.adjust_tag_count(tag, 1)?;
+        }
+        for &tag in &tags {
+            self.touch_tag(tag)?;
+        }
+
+        Ok(ids)
+    }
 
-        self.tag_name_ids.remove(old_name)?;
+    /// Apply many (item, tag name) pairs at once, creating tags as needed.
+    ///
+    /// Names are resolved up front, then each index is written with a single sled `Batch`,
+    /// which is much cheaper than tagging one pair at a time. Each index's batch is atomic, but
+    /// a crash between the two can leave the reverse index missing some of the new entries.
+    pub fn tag_batch(&self, ops: &[(ItemID, &str)]) -> Result<()> {
+        self.writable()?;
+        let mut resolved: HashMap<&str, Vec<TagID>> = HashMap::new();
+        for &(_, name) in ops {
+            if !resolved.contains_key(name) {
+                let tag = self.intern_tag(name)?;
+                resolved.insert(name, self.implied_tags(tag)?);
+            }
+        }
+
+        let mut forward = sled::Batch::default();
+        let mut reverse = sled::Batch::default();
+        let mut touched: HashSet<TagID> = HashSet::new();
+        for &(item, name) in ops {
+            for &tag in &resolved[name] {
+                forward.insert(&compound_key(tag, item)[..], PRESENT);
+                reverse.insert(&compound_key(item, tag)[..], PRESENT);
+                touched.insert(tag);
+            }
+        }
+
+        self.tag_items.apply_batch(forward)?;
+        if self.reverse_index {
+            self.item_tags.apply_batch(reverse)?;
+        }
+
+        // Batches don't say which keys were new, so recount the tags we touched
+        for tag in touched {
+            self.touch_tag(tag)?;
+            self.invalidate_tag_count(tag)?;
+        }
 
         Ok(())
     }
 
-    pub fn get_item_tag_ids(&self, id: ItemID) -> impl Iterator<Item = Result<TagID>> + '_ {
-        let item_tags_iter = self.item_tags.scan_prefix(id.to_bytes());
+    /// Make anything tagged `when` also get tagged `implies` from now on.
+    pub fn add_implication(&self, when: TagID, implies: TagID) -> Result<()> {
+        self.writable()?;
+        self.implications
+            .insert(compound_key(when, implies), PRESENT)?;
+        Ok(())
+    }
 
-        item_tags_iter.map(move |el| -> Result<TagID> {
-            // Get the tag key from the compound key
-            let (key_vec, _val) = el?;
-            let (_item_id, tag_id): (ItemID, TagID) = from_compound_key(&must_u8_16(&key_vec)?);
-            Ok(tag_id)
-        })
+    pub fn remove_implication(&self, when: TagID, implies: TagID) -> Result<()> {
+        self.writable()?;
+        self.implications.remove(compound_key(when, implies))?;
+        Ok(())
     }
 
-    pub fn get_item_tags(&self, id: ItemID) -> impl Iterator<Item = Result<Tag>> + '_ {
-        let tags_iter = self.get_item_tag_ids(id);
+    /// `tag` followed by every tag it transitively implies, each once. Cycles are fine.
+    pub fn implied_tags(&self, tag: TagID) -> Result<Vec<TagID>> {
+        let mut seen: HashSet<TagID> = HashSet::new();
+        seen.insert(tag);
 
-        tags_iter.map(move |tag_result| -> Result<Tag> {
-            let tag_id = tag_result?;
+        let mut tags = vec![tag];
+        let mut next = 0;
+        while next < tags.len() {
+            let current = tags[next];
+            next += 1;
 
-            // Join to get the tag name
-            let tag_vec = self
-                .tag_id_names
-                .get(tag_id.to_bytes())?
-                .ok_or(Error::InternalError {})?;
+            for el in self.implications.scan_prefix(current.to_bytes()) {
+                let (key_vec, _val) = el?;
+                let (_when, implied): (TagID, TagID) =
+                    from_compound_key(&must_u8_16(&key_vec, "implications")?);
+                if seen.insert(implied) {
+                    tags.push(implied);
+                }
+            }
+        }
 
-            let tag_name: &str = std::str::from_utf8(&tag_vec)
-                .map_err(|_| snafu::NoneError)
-                .context(InternalError)?;
+        Ok(tags)
+    }
 
-            Ok(Tag {
-                id: tag_id,
-                name: tag_name.to_string(),
-            })
-        })
+    /// Record that `tag` was just applied, keeping the latest timestamp seen.
+    fn touch_tag(&self, tag: TagID) -> Result<()> {
+        let now = Utc::now().timestamp_millis();
+
+        self.tag_meta
+            .fetch_and_update(tag_meta_key(tag, TAG_META_LAST_USED), |old| {
+                let last = old
+                    .and_then(|bytes| must_u8_8(bytes, "tag_meta").ok())
+                    .map(i64::from_be_bytes)
+                    .map_or(now, |last| last.max(now));
+                Some(last.to_be_bytes().to_vec())
+            })?;
+
+        Ok(())
     }
 
-    pub fn get_tag_item_ids(&self, id: TagID) -> impl Iterator<Item = Result<ItemID>> + '_ {
-        let tag_items_iter = self.tag_items.scan_prefix(id.to_bytes());
+    /// When `tag` was last applied to an item, if it has been since tracking began.
+    pub fn tag_last_used(&self, tag: TagID) -> Result<Option<DateTime<Utc>>> {
+        let found: Option<IVec> = self.tag_meta.get(tag_meta_key(tag, TAG_META_LAST_USED))?;
 
-        tag_items_iter.map(move |el| -> Result<ItemID> {
-            // Get the item key from the compound key
-            let (key_vec, _val) = el?;
-            let (_tag_id, item_id): (TagID, ItemID) = from_compound_key(&must_u8_16(&key_vec)?);
+        match found {
+            None => Ok(None),
+            Some(vec) => {
+                let millis = i64::from_be_bytes(must_u8_8(&vec, "tag_meta")?);
+                Ok(Some(Utc.timestamp_millis(millis)))
+            }
+        }
+    }
 
-            Ok(item_id)
-        })
+    /// When `tag` was created, if that was recorded. Tags made before creation times were
+    /// tracked have none.
+    pub fn tag_created_at(&self, tag: TagID) -> Result<Option<DateTime<Utc>>> {
+        let found: Option<IVec> = self.tag_meta.get(tag_meta_key(tag, TAG_META_CREATED))?;
+
+        match found {
+            None => Ok(None),
+            Some(vec) => {
+                let millis = i64::from_be_bytes(must_u8_8(&vec, "tag_meta")?);
+                Ok(Some(Utc.timestamp_millis(millis)))
+            }
+        }
     }
-}
 
-fn compound_key<T1: Id, T2: Id>(a: T1, b: T2) -> [u8; 16] {
-    let a_bytes: [u8; 8] = a.into();
-    let b_bytes: [u8; 8] = b.into();
-    let mut dest: [u8; 16] = [0; 16];
+    /// All tags not applied since `cutoff`, along with when they were last used. Tags with no
+    /// recorded use are included.
+    pub fn stale_tags(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> impl Iterator<Item = Result<(Tag, Option<DateTime<Utc>>)>> + '_ {
+        self.tag_id_names
+            .iter()
+            .map(move |el| -> Result<Option<(Tag, Option<DateTime<Utc>>)>> {
+                let (key_vec, name_vec) = el?;
+                let tag = tag_from_entry(&key_vec, &name_vec, "tag_id_names")?;
 
-    for i in 0..8 {
-        dest[i] = a_bytes[i];
-        dest[8 + i] = b_bytes[i];
+                let last_used = self.tag_last_used(tag.id)?;
+                match last_used {
+                    Some(time) if time >= cutoff => Ok(None),
+                    _ => Ok(Some((tag, last_used))),
+                }
+            })
+            .filter_map(Result::transpose)
     }
 
-    dest
-}
+    /// Remove `tag` from `item`, from both indices in one transaction. Returns whether the item
+    /// actually had the tag.
+    pub fn untag(&self, item: ItemID, tag: TagID) -> Result<bool> {
+        self.writable()?;
+        let reverse_index = self.reverse_index;
 
-fn from_compound_key<T1: Id, T2: Id>(compound: &[u8; 16]) -> (T1, T2) {
-    let mut a_bytes: [u8; 8] = [0u8; 8];
-    let mut b_bytes: [u8; 8] = [0u8; 8];
+        let removed = (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<bool> {
+                let old = tag_items.remove(&compound_key(tag, item)[..])?;
+                if reverse_index {
+                    item_tags.remove(&compound_key(item, tag)[..])?;
+                }
+                Ok(old.is_some())
+            },
+        )?;
+        if removed {
+            self.adjust_tag_count(tag, -1)?;
+        }
 
-    for i in 0..8 {
-        a_bytes[i] = compound[i];
-        b_bytes[i] = compound[8 + i];
+        Ok(removed)
     }
 
-    (T1::from(a_bytes), T2::from(b_bytes))
-}
+    /// Make `tags` (and the tags they imply) exactly the tags on `item`, creating any that don't
+    /// exist. Only the difference from the item's current tags is written, in one transaction,
+    /// so the item never has a mix of old and new tags. The current tags are read before the
+    /// transaction, so a concurrent change to the same item may be overwritten.
+    ///
+    /// Fails with `Error::ReverseIndexDisabled` if the store doesn't keep the item -> tags index.
+    pub fn set_item_tags(&self, item: ItemID, tags: &[&str]) -> Result<()> {
+        self.writable()?;
+        let current = self
+            .get_item_tag_ids(item)
+            .collect::<Result<HashSet<TagID>>>()?;
 
-fn must_u8_16(slice: &[u8]) -> Result<[u8; 16]> {
-    let arr: [u8; 16] = slice
-        .try_into()
-        .map_err(|_| snafu::NoneError)
-        .context(InternalError)?;
-    Ok(arr)
-}
+        let mut wanted: HashSet<TagID> = HashSet::new();
+        for name in tags {
+            let tag = self.intern_tag(name)?;
+            wanted.extend(self.implied_tags(tag)?);
+        }
 
-fn must_u8_8(slice: &[u8]) -> Result<[u8; 8]> {
-    let arr: [u8; 8] = slice
-        .try_into()
-        .map_err(|_| snafu::NoneError)
-        .context(InternalError)?;
-    Ok(arr)
-}
+        let added: Vec<TagID> = wanted.difference(&current).copied().collect();
+        let removed: Vec<TagID> = current.difference(&wanted).copied().collect();
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
 
-generate_id!(TagID);
-generate_id!(ItemID);
+        (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<()> {
+                for &tag in &added {
+                    tag_items.insert(&compound_key(tag, item)[..], PRESENT)?;
+                    item_tags.insert(&compound_key(item, tag)[..], PRESENT)?;
+                }
+                for &tag in &removed {
+                    tag_items.remove(&compound_key(tag, item)[..])?;
+                    item_tags.remove(&compound_key(item, tag)[..])?;
+                }
+                Ok(())
+            },
+        )?;
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Tag {
-    pub id: TagID,
-    pub name: String,
+        for &tag in &added {
+            self.adjust_tag_count(tag, 1)?;
+            self.touch_tag(tag)?;
+        }
+        for &tag in &removed {
+            self.adjust_tag_count(tag, -1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of items tagged `tag`. This is kept up to date as items are tagged, so it's cheap
+    /// except for the first call on a tag that hasn't been counted yet.
+    pub fn tag_item_count(&self, tag: TagID) -> Result<u64> {
+        if let Some(count) = self.tag_counts.get(tag.to_bytes())? {
+            return Ok(u64::from_be_bytes(must_u8_8(&count, "tag_counts")?));
+        }
+
+        let count = self.tag_items.scan_prefix(tag.to_bytes()).count() as u64;
+        if !self.read_only {
+            self.tag_counts
+                .insert(tag.to_bytes(), &count.to_be_bytes())?;
+        }
+        Ok(count)
+    }
+
+    /// Number of tags on `item`. This counts the item's entries in the item -> tags index.
+    pub fn item_tag_count(&self, item: ItemID) -> Result<u64> {
+        let mut count: u64 = 0;
+        for tag_result in self.get_item_tag_ids(item) {
+            tag_result?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Whether `item` has any tags, without counting them.
+    pub fn item_exists(&self, item: ItemID) -> Result<bool> {
+        if !self.reverse_index {
+            return Err(Error::ReverseIndexDisabled {});
+        }
+
+        match self.item_tags.scan_prefix(item.to_bytes()).next() {
+            Some(el) => {
+                el?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether `item` has any attributes or counters set.
+    pub fn item_has_attrs(&self, item: ItemID) -> Result<bool> {
+        match self.item_attrs.scan_prefix(item.to_bytes()).next() {
+            Some(el) => {
+                el?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Change a tag's item count, if it's been counted. Uncounted tags are counted in full the
+    /// next time they're asked for.
+    fn adjust_tag_count(&self, tag: TagID, by: i64) -> Result<()> {
+        self.tag_counts.update_and_fetch(tag.to_bytes(), |old| {
+            let count = u64::from_be_bytes(must_u8_8(old?, "tag_counts").ok()?);
+            Some((count as i64 + by).max(0).to_be_bytes().to_vec())
+        })?;
+        Ok(())
+    }
+
+    /// Forget a tag's item count, so it's recounted next time. Used after bulk changes.
+    fn invalidate_tag_count(&self, tag: TagID) -> Result<()> {
+        self.tag_counts.remove(tag.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn update_tag(&self, id: TagID, name: &str) -> Result<Tag> {
+        self.writable()?;
+        let tag = Tag::new(id, name.to_string());
+
+        let old_name = self.tag_id_names.insert(&id.to_bytes(), name.as_bytes())?;
+        let key = self.name_key(name.as_bytes());
+        self.tag_name_ids.insert(&key[..], &id.to_bytes())?;
+        self.cache_tag_id(name, id);
+
+        // If this gave an existing tag a new name, don't leave the old one resolving to it.
+        if let Some(old_name) = old_name.filter(|old| self.name_key(old) != key) {
+            let old_key = self.name_key(&old_name);
+            if self.tag_name_ids.get(&old_key)? == Some(IVec::from(&id.to_bytes()[..])) {
+                self.tag_name_ids.remove(old_key)?;
+            }
+            if let Ok(old_name) = std::str::from_utf8(&old_name) {
+                self.uncache_tag_name(old_name);
+            }
+        }
+
+        Ok(tag)
+    }
+
+    /// Delete a tag entirely: its associations with every item, then its name.
+    pub fn remove_tag(&self, id: TagID) -> Result<()> {
+        self.writable()?;
+        let old_name: IVec = match self.tag_id_names.get(&id.to_bytes())? {
+            Some(name) => name,
+            None => return Err(Error::TagNotFound { id }),
+        };
+
+        let items = self.get_tag_item_ids(id).collect::<Result<Vec<ItemID>>>()?;
+        let reverse_index = self.reverse_index;
+        let old_key = self.name_key(&old_name);
+        let aliases = self.tag_aliases(id)?;
+        let alias_keys: Vec<Vec<u8>> = aliases
+            .iter()
+            .map(|alias| self.name_key(alias.as_bytes()))
+            .collect();
+
+        let trees = (
+            &self.tag_items,
+            &self.item_tags,
+            &self.tag_id_names,
+            &self.tag_name_ids,
+        );
+        trees.transaction(|trees| -> ConflictableTransactionResult<()> {
+            let (tag_items, item_tags, tag_id_names, tag_name_ids) = trees;
+            for &item in &items {
+                tag_items.remove(&compound_key(id, item)[..])?;
+                if reverse_index {
+                    item_tags.remove(&compound_key(item, id)[..])?;
+                }
+            }
+
+            tag_id_names.remove(&id.to_bytes()[..])?;
+            tag_name_ids.remove(&old_key[..])?;
+            for key in &alias_keys {
+                tag_name_ids.remove(&key[..])?;
+            }
+            Ok(())
+        })?;
+
+        if let Ok(name) = std::str::from_utf8(&old_name) {
+            self.uncache_tag_name(name);
+        }
+        for alias in &aliases {
+            self.uncache_tag_name(alias);
+        }
+        self.remove_tag_meta(id)?;
+        self.invalidate_tag_count(id)?;
+        self.repoint_implications(id, None)?;
+
+        Ok(())
+    }
+
+    /// Take `tag` off every item, in one transaction, but keep the tag itself: its name, its
+    /// metadata, and any implication rules. Returns how many items had it.
+    pub fn clear_tag(&self, tag: TagID) -> Result<u64> {
+        self.writable()?;
+        if !self.tag_id_exists(tag)? {
+            return Err(Error::TagNotFound { id: tag });
+        }
+
+        let items = self
+            .get_tag_item_ids(tag)
+            .collect::<Result<Vec<ItemID>>>()?;
+        let reverse_index = self.reverse_index;
+
+        (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<()> {
+                for &item in &items {
+                    tag_items.remove(&compound_key(tag, item)[..])?;
+                    if reverse_index {
+                        item_tags.remove(&compound_key(item, tag)[..])?;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        self.invalidate_tag_count(tag)?;
+
+        Ok(items.len() as u64)
+    }
+
+    fn remove_tag_meta(&self, tag: TagID) -> Result<()> {
+        for el in self.tag_meta.scan_prefix(tag.to_bytes()) {
+            let (key_vec, _val) = el?;
+            self.tag_meta.remove(key_vec)?;
+        }
+        Ok(())
+    }
+
+    /// Point implication rules that mention `from` at `into` instead, or drop them if `into` is
+    /// `None`.
+    fn repoint_implications(&self, from: TagID, into: Option<TagID>) -> Result<()> {
+        for el in self.implications.iter() {
+            let (key_vec, _val) = el?;
+            let (when, implies): (TagID, TagID) =
+                from_compound_key(&must_u8_16(&key_vec, "implications")?);
+            if when != from && implies != from {
+                continue;
+            }
+
+            self.implications.remove(&key_vec)?;
+            if let Some(into) = into {
+                let when = if when == from { into } else { when };
+                let implies = if implies == from { into } else { implies };
+                if when != implies {
+                    self.implications
+                        .insert(compound_key(when, implies), PRESENT)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete an item: its tags, attributes, metadata, and favorite flag. Returns how many tags
+    /// it had.
+    ///
+    /// Numeric tags are ordered by value, not item, so finding the item's ones walks that whole
+    /// index. Without the item -> tags index, finding its tags walks the tag -> items index too.
+    pub fn remove_item(&self, item: ItemID) -> Result<usize> {
+        self.writable()?;
+        let reverse_index = self.reverse_index;
+        let tags = if reverse_index {
+            self.get_item_tag_ids(item)
+                .collect::<Result<Vec<TagID>>>()?
+        } else {
+            let mut tags = Vec::new();
+            for key_result in self.tag_items.iter().keys() {
+                let key_vec = key_result?;
+                let (tag, tagged): (TagID, ItemID) =
+                    from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+                if tagged == item {
+                    tags.push(tag);
+                }
+            }
+            tags
+        };
+        let attrs = self
+            .item_attrs
+            .scan_prefix(item.to_bytes())
+            .keys()
+            .collect::<std::result::Result<Vec<IVec>, _>>()?;
+        let mut numbers = Vec::new();
+        for key_result in self.numeric_tags.iter().keys() {
+            let key_vec = key_result?;
+            if key_vec[key_vec.len().saturating_sub(ID_LEN)..] == item.to_bytes() {
+                numbers.push(key_vec);
+            }
+        }
+        let persistent_id = self.get_item_attr(item, ATTR_PERSISTENT_ID)?;
+
+        let trees = (
+            &self.tag_items,
+            &self.item_tags,
+            &self.item_attrs,
+            &self.favorites,
+            &self.numeric_tags,
+            &self.item_meta,
+        );
+        trees.transaction(|trees| -> ConflictableTransactionResult<()> {
+            let (tag_items, item_tags, item_attrs, favorites, numeric_tags, item_meta) = trees;
+            for &tag in &tags {
+                if reverse_index {
+                    item_tags.remove(&compound_key(item, tag)[..])?;
+                }
+                tag_items.remove(&compound_key(tag, item)[..])?;
+            }
+            for key in &attrs {
+                item_attrs.remove(key)?;
+            }
+            for key in &numbers {
+                numeric_tags.remove(key)?;
+            }
+            favorites.remove(&item.to_bytes()[..])?;
+            item_meta.remove(&item.to_bytes()[..])?;
+            Ok(())
+        })?;
+        if let Some(persistent_id) = persistent_id {
+            self.persistent_ids.remove(persistent_id.as_bytes())?;
+        }
+
+        for &tag in &tags {
+            self.invalidate_tag_count(tag)?;
+        }
+
+        Ok(tags.len())
+    }
+
+    /// Give a tag a new name, keeping its ID and associations. The old name stops resolving.
+    pub fn rename_tag(&self, id: TagID, new_name: &str) -> Result<()> {
+        self.writable()?;
+        let old_name: IVec = self
+            .tag_id_names
+            .get(id.to_bytes())?
+            .ok_or(Error::TagNotFound { id })?;
+
+        match self.get_tag_id(new_name)? {
+            // Under case-insensitivity this may still change how the name is displayed
+            Some(existing) if existing == id && &old_name[..] == new_name.as_bytes() => {
+                return Ok(())
+            }
+            Some(existing) if existing == id => {}
+            Some(_) => {
+                return Err(Error::NameInUse {
+                    name: new_name.to_string(),
+                })
+            }
+            None => {}
+        }
+
+        let old_key = self.name_key(&old_name);
+        let new_key = self.name_key(new_name.as_bytes());
+        (&self.tag_id_names, &self.tag_name_ids).transaction(
+            |(tag_id_names, tag_name_ids)| -> ConflictableTransactionResult<()> {
+                tag_name_ids.remove(&old_key[..])?;
+                tag_name_ids.insert(&new_key[..], &id.to_bytes()[..])?;
+                tag_id_names.insert(&id.to_bytes()[..], new_name.as_bytes())?;
+                Ok(())
+            },
+        )?;
+
+        if let Ok(name) = std::str::from_utf8(&old_name) {
+            self.uncache_tag_name(name);
+        }
+        self.cache_tag_id(new_name, id);
+        // If the new name was one of the tag's aliases, it's now just its name
+        self.tag_meta
+            .remove(tag_meta_key(id, &alias_field(new_name)))?;
+
+        Ok(())
+    }
+
+    /// Fold `from` into `into`: every item tagged `from` ends up tagged `into`, and `from` is
+    /// deleted. Returns how many items gained `into`; items that already had both aren't counted.
+    /// Both tags must exist.
+    pub fn merge_tags(&self, from: TagID, into: TagID) -> Result<u64> {
+        self.writable()?;
+        if from == into {
+            return Ok(0);
+        }
+
+        let from_name: IVec = self
+            .tag_id_names
+            .get(from.to_bytes())?
+            .ok_or(Error::TagNotFound { id: from })?;
+        if !self.tag_id_names.contains_key(into.to_bytes())? {
+            return Err(Error::TagNotFound { id: into });
+        }
+        let items = self
+            .get_tag_item_ids(from)
+            .collect::<Result<Vec<ItemID>>>()?;
+        let reverse_index = self.reverse_index;
+        let from_key = self.name_key(&from_name);
+
+        let trees = (
+            &self.tag_items,
+            &self.item_tags,
+            &self.tag_id_names,
+            &self.tag_name_ids,
+        );
+        let moved = trees.transaction(|trees| -> ConflictableTransactionResult<u64> {
+            let (tag_items, item_tags, tag_id_names, tag_name_ids) = trees;
+            let mut moved: u64 = 0;
+            for &item in &items {
+                tag_items.remove(&compound_key(from, item)[..])?;
+                let old = tag_items.insert(&compound_key(into, item)[..], PRESENT)?;
+                if reverse_index {
+                    item_tags.remove(&compound_key(item, from)[..])?;
+                    item_tags.insert(&compound_key(item, into)[..], PRESENT)?;
+                }
+
+                if old.is_none() {
+                    moved += 1;
+                }
+            }
+
+            tag_id_names.remove(&from.to_bytes()[..])?;
+            tag_name_ids.remove(&from_key[..])?;
+            Ok(moved)
+        })?;
+
+        if let Ok(name) = std::str::from_utf8(&from_name) {
+            self.uncache_tag_name(name);
+        }
+
+        // Keep the latest use of either tag, and drop the rest of `from`'s metadata
+        if let Some(last_used) = self.tag_meta.get(tag_meta_key(from, TAG_META_LAST_USED))? {
+            let from_last = i64::from_be_bytes(must_u8_8(&last_used, "tag_meta")?);
+            self.tag_meta
+                .fetch_and_update(tag_meta_key(into, TAG_META_LAST_USED), |old| {
+                    let last = old
+                        .and_then(|bytes| must_u8_8(bytes, "tag_meta").ok())
+                        .map(i64::from_be_bytes)
+                        .map_or(from_last, |last| last.max(from_last));
+                    Some(last.to_be_bytes().to_vec())
+                })?;
+        }
+        // The merged tag has existed since the older of the two was created
+        if let Some(created) = self.tag_meta.get(tag_meta_key(from, TAG_META_CREATED))? {
+            let from_created = i64::from_be_bytes(must_u8_8(&created, "tag_meta")?);
+            self.tag_meta
+                .fetch_and_update(tag_meta_key(into, TAG_META_CREATED), |old| {
+                    let created = old
+                        .and_then(|bytes| must_u8_8(bytes, "tag_meta").ok())
+                        .map(i64::from_be_bytes)
+                        .map_or(from_created, |created| created.min(from_created));
+                    Some(created.to_be_bytes().to_vec())
+                })?;
+        }
+        // Aliases of `from` now resolve to `into`
+        for alias in self.tag_aliases(from)? {
+            self.tag_name_ids
+                .insert(self.name_key(alias.as_bytes()), &into.to_bytes()[..])?;
+            self.tag_meta
+                .insert(tag_meta_key(into, &alias_field(&alias)), PRESENT)?;
+            self.cache_tag_id(&alias, into);
+        }
+        self.remove_tag_meta(from)?;
+        self.invalidate_tag_count(from)?;
+        self.invalidate_tag_count(into)?;
+        self.repoint_implications(from, Some(into))?;
+
+        Ok(moved)
+    }
+
+    /// Rename every tag starting with `old_prefix` to start with `new_prefix` instead, keeping
+    /// IDs and associations. Returns how many tags were renamed or merged.
+    pub fn rename_prefix(
+        &self,
+        old_prefix: &str,
+        new_prefix: &str,
+        on_collision: PrefixCollision,
+    ) -> Result<usize> {
+        self.writable()?;
+        let mut renames: Vec<(TagID, String)> = Vec::new();
+        for tag in self.tags_with_prefix(old_prefix, usize::MAX)? {
+            let rest = match self.strip_name_prefix(&tag.name, old_prefix) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            renames.push((tag.id, format!("{}{}", new_prefix, rest)));
+        }
+        let renamed = renames.len();
+
+        // A name held by another tag being renamed is freed up once that tag moves, so only
+        // tags outside the rename count as collisions
+        let mut pending: HashSet<TagID> = renames.iter().map(|(id, _)| *id).collect();
+        if on_collision == PrefixCollision::Error {
+            for (id, new_name) in &renames {
+                match self.get_tag_id(new_name)? {
+                    Some(existing) if existing != *id && !pending.contains(&existing) => {
+                        return Err(Error::NameInUse {
+                            name: new_name.clone(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Targets are resolved as each rename is applied. A tag whose new name still belongs to
+        // a pending tag waits for that one to move first. Names only get longer or only get
+        // shorter along such a chain, so it always ends.
+        while !renames.is_empty() {
+            let before = renames.len();
+            let mut waiting = Vec::new();
+            for (id, new_name) in renames.drain(..) {
+                match self
+                    .get_tag_id(&new_name)?
+                    .filter(|&existing| existing != id)
+                {
+                    Some(existing) if pending.contains(&existing) => {
+                        waiting.push((id, new_name));
+                        continue;
+                    }
+                    Some(existing) => {
+                        self.merge_tags(id, existing)?;
+                    }
+                    None => self.rename_tag(id, &new_name)?,
+                }
+                pending.remove(&id);
+            }
+
+            if waiting.len() == before {
+                return Err(Error::InternalError {});
+            }
+            renames = waiting;
+        }
+
+        Ok(renamed)
+    }
+
+    /// Up to `limit` tags whose names start with `prefix`, in lexical order of name. In a
+    /// case-insensitive store the prefix matches regardless of case too. Aliases aren't matched.
+    pub fn tags_with_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<Tag>> {
+        let mut tags = Vec::new();
+        for el in self
+            .tag_name_ids
+            .scan_prefix(self.name_key(prefix.as_bytes()))
+        {
+            if tags.len() >= limit {
+                break;
+            }
+
+            // The key may be lowercased or an alias, so take the name from the other index
+            let (key_vec, id_vec) = el?;
+            let id = TagID::from(must_u8_8(&id_vec, "tag_name_ids")?);
+            let name = self
+                .tag_id_names
+                .get(id.to_bytes())?
+                .ok_or(Error::TagNotFound { id })?;
+            if self.name_key(&name)[..] != key_vec[..] {
+                continue;
+            }
+            tags.push(tag_from_entry(&id_vec, &name, "tag_id_names")?);
+        }
+        Ok(tags)
+    }
+
+    /// The rest of `name` after `prefix`, matched the way tag names are. A case-insensitive
+    /// match may not share the prefix byte for byte, since a character's other case can have a
+    /// different UTF-8 length, so this walks `name` a character at a time. `None` if `name`
+    /// doesn't start with `prefix`.
+    fn strip_name_prefix<'a>(&self, name: &'a str, prefix: &str) -> Option<&'a str> {
+        if !self.case_insensitive {
+            return if name.starts_with(prefix) {
+                Some(&name[prefix.len()..])
+            } else {
+                None
+            };
+        }
+
+        let target = prefix.to_lowercase();
+        let mut folded = String::new();
+        for (i, c) in name.char_indices() {
+            if folded == target {
+                return Some(&name[i..]);
+            }
+            if !target.starts_with(folded.as_str()) {
+                return None;
+            }
+            folded.extend(c.to_lowercase());
+        }
+
+        if folded == target {
+            Some("")
+        } else {
+            None
+        }
+    }
+
+    /// Give a tag a description for people to read, replacing any it had.
+    pub fn set_tag_description(&self, id: TagID, description: &str) -> Result<()> {
+        self.writable()?;
+        if !self.tag_id_exists(id)? {
+            return Err(Error::TagNotFound { id });
+        }
+        self.tag_meta.insert(
+            tag_meta_key(id, TAG_META_DESCRIPTION),
+            description.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The description set by `set_tag_description`, if any.
+    pub fn tag_description(&self, id: TagID) -> Result<Option<String>> {
+        let found: Option<IVec> = self.tag_meta.get(tag_meta_key(id, TAG_META_DESCRIPTION))?;
+        Ok(found.map(|vec| String::from_utf8_lossy(&vec).into_owned()))
+    }
+
+    /// Make `alias` another name for the tag `id`, so `get_tag_id(alias)` (and so tagging by
+    /// name) resolves to it. The tag keeps its own name for display. Fails with
+    /// `Error::NameInUse` if `alias` is already the name or an alias of a different tag.
+    pub fn add_tag_alias(&self, id: TagID, alias: &str) -> Result<()> {
+        self.writable()?;
+        if !self.tag_id_exists(id)? {
+            return Err(Error::TagNotFound { id });
+        }
+
+        let claimed = self.tag_name_ids.compare_and_swap(
+            self.name_key(alias.as_bytes()),
+            None as Option<&[u8]>,
+            Some(&id.to_bytes()[..]),
+        )?;
+        if let Err(lost) = claimed {
+            // If the name already resolves to this tag, there's nothing to add
+            return match lost.current {
+                Some(existing) if existing[..] == id.to_bytes() => Ok(()),
+                _ => Err(Error::NameInUse {
+                    name: alias.to_string(),
+                }),
+            };
+        }
+
+        self.tag_meta
+            .insert(tag_meta_key(id, &alias_field(alias)), PRESENT)?;
+        self.cache_tag_id(alias, id);
+        Ok(())
+    }
+
+    /// Every alias added to the tag `id` with `add_tag_alias`, in lexical order.
+    pub fn tag_aliases(&self, id: TagID) -> Result<Vec<String>> {
+        let prefix = tag_meta_key(id, TAG_META_ALIAS);
+        let mut aliases = Vec::new();
+        for key_result in self.tag_meta.scan_prefix(&prefix).keys() {
+            let key_vec = key_result?;
+            aliases.push(String::from_utf8_lossy(&key_vec[prefix.len()..]).into_owned());
+        }
+        Ok(aliases)
+    }
+
+    /// Tags whose names look like `needle`, ignoring case, best match first. A tag matches if
+    /// its name contains `needle`, or is within a few typos of it; containing matches come first,
+    /// then closer names. This reads every tag name, so it's meant for suggestions, not queries.
+    pub fn search_tags(&self, needle: &str) -> Result<Vec<Tag>> {
+        let needle = needle.to_lowercase();
+        let max_distance = std::cmp::max(1, needle.chars().count() / 3);
+
+        let mut found: Vec<(bool, usize, Tag)> = Vec::new();
+        for tag_result in self.all_tags() {
+            let tag = tag_result?;
+            let name = tag.name.to_lowercase();
+            let contains = name.contains(&needle);
+            let distance = edit_distance(&name, &needle);
+            if contains || distance <= max_distance {
+                found.push((!contains, distance, tag));
+            }
+        }
+
+        found.sort_by(|(a_far, a_distance, a), (b_far, b_distance, b)| {
+            (a_far, a_distance, &a.name).cmp(&(b_far, b_distance, &b.name))
+        });
+        Ok(found.into_iter().map(|(_, _, tag)| tag).collect())
+    }
+
+    /// Items tagged `tag_prefix` or anything beneath it in the tag hierarchy: asking for
+    /// `genre/classical` also finds `genre/classical/baroque`, but not `genre/classicalish`.
+    /// Each item comes up once, in ID order.
+    ///
+    /// Every `TAG_PATH_SEPARATOR` in a name separates two levels, so existing tags like
+    /// `AC/DC` are read as `DC` beneath `AC`. Levels don't need to exist as tags themselves.
+    pub fn get_items_under(
+        &self,
+        tag_prefix: &str,
+    ) -> Result<impl Iterator<Item = Result<ItemID>>> {
+        let mut tags: Vec<TagID> = self.get_tag_id(tag_prefix)?.into_iter().collect();
+        let children = format!("{}{}", tag_prefix, TAG_PATH_SEPARATOR);
+        for tag in self.tags_with_prefix(&children, usize::MAX)? {
+            tags.push(tag.id);
+        }
+
+        Ok(self.get_items_with_any_tag(&tags))
+    }
+
+    /// The full names of the levels directly beneath `parent` in the tag hierarchy, in lexical
+    /// order; an empty `parent` gives the top level. A level is listed if any tag is at or
+    /// below it, even if there's no tag with exactly that name. See `get_items_under`.
+    pub fn child_tags(&self, parent: &str) -> Result<Vec<String>> {
+        let prefix = match parent {
+            "" => String::new(),
+            parent => format!("{}{}", parent, TAG_PATH_SEPARATOR),
+        };
+
+        let mut children: BTreeSet<String> = BTreeSet::new();
+        for tag in self.tags_with_prefix(&prefix, usize::MAX)? {
+            let rest = match self.strip_name_prefix(&tag.name, &prefix) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            if let Some(level) = rest.split(TAG_PATH_SEPARATOR).next() {
+                children.insert(format!("{}{}", prefix, level));
+            }
+        }
+
+        Ok(children.into_iter().collect())
+    }
+
+    /// Atomically add `by` to a numeric item attribute, returning the new value. Missing (or
+    /// non-numeric) attributes count as 0.
+    pub fn increment_item_counter(&self, item: ItemID, key: &str, by: i64) -> Result<i64> {
+        self.writable()?;
+        let updated = self
+            .item_attrs
+            .update_and_fetch(item_attr_key(item, key), |old| {
+                let value = old
+                    .and_then(|bytes| must_u8_8(bytes, "item_attrs").ok())
+                    .map(decode_number)
+                    .unwrap_or(0);
+                Some(encode_number(value.wrapping_add(by)).to_vec())
+            })?
+            .ok_or(Error::InternalError {})?;
+
+        Ok(decode_number(must_u8_8(&updated, "item_attrs")?))
+    }
+
+    /// Set a numeric item attribute.
+    pub fn set_item_counter(&self, item: ItemID, key: &str, value: i64) -> Result<()> {
+        self.writable()?;
+        self.item_attrs
+            .insert(item_attr_key(item, key), &encode_number(value))?;
+        Ok(())
+    }
+
+    /// Read a numeric item attribute written by `increment_item_counter`.
+    pub fn get_item_counter(&self, item: ItemID, key: &str) -> Result<Option<i64>> {
+        let found: Option<IVec> = self.item_attrs.get(item_attr_key(item, key))?;
+
+        match found {
+            None => Ok(None),
+            Some(vec) => Ok(Some(decode_number(must_u8_8(&vec, "item_attrs")?))),
+        }
+    }
+
+    /// Remove a numeric item attribute. Returns whether it was set.
+    pub fn remove_item_counter(&self, item: ItemID, key: &str) -> Result<bool> {
+        self.writable()?;
+        Ok(self.item_attrs.remove(item_attr_key(item, key))?.is_some())
+    }
+
+    /// Set a string item attribute.
+    pub fn set_item_attr(&self, item: ItemID, key: &str, value: &str) -> Result<()> {
+        self.writable()?;
+        self.item_attrs
+            .insert(item_attr_key(item, key), value.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read a string item attribute written by `set_item_attr`.
+    pub fn get_item_attr(&self, item: ItemID, key: &str) -> Result<Option<String>> {
+        let found: Option<IVec> = self.item_attrs.get(item_attr_key(item, key))?;
+
+        match found {
+            None => Ok(None),
+            Some(vec) => {
+                let value: &str = std::str::from_utf8(&vec)
+                    .map_err(|_| snafu::NoneError)
+                    .context(InternalError)?;
+                Ok(Some(value.to_string()))
+            }
+        }
+    }
+
+    /// Read a string item attribute, or `None` if it isn't set or isn't text. Counters from 0 up
+    /// to 2^62 are encoded starting with a byte UTF-8 text never starts with (see
+    /// `encode_number`), so they always read as `None`; a negative counter might not.
+    pub fn get_item_attr_text(&self, item: ItemID, key: &str) -> Result<Option<String>> {
+        let found: Option<IVec> = self.item_attrs.get(item_attr_key(item, key))?;
+        Ok(found.and_then(|vec| String::from_utf8(vec.to_vec()).ok()))
+    }
+
+    /// Record that `item` was imported from the track with this persistent ID, so importing the
+    /// track again can find it. Any item previously recorded for the same ID is forgotten.
+    pub fn set_persistent_id(&self, item: ItemID, persistent_id: &str) -> Result<()> {
+        self.writable()?;
+        self.persistent_ids
+            .insert(persistent_id.as_bytes(), &item.to_bytes()[..])?;
+        self.set_item_attr(item, ATTR_PERSISTENT_ID, persistent_id)
+    }
+
+    /// The item imported from the track with this persistent ID, if there is one.
+    pub fn item_by_persistent_id(&self, persistent_id: &str) -> Result<Option<ItemID>> {
+        match self.persistent_ids.get(persistent_id.as_bytes())? {
+            Some(vec) => Ok(Some(ItemID::from(must_u8_8(&vec, "persistent_ids")?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the display name of an item, e.g. the track name it was imported from.
+    pub fn set_item_meta(&self, item: ItemID, name: &str) -> Result<()> {
+        self.writable()?;
+        self.item_meta.insert(item.to_bytes(), name.as_bytes())?;
+        Ok(())
+    }
+
+    /// The display name of an item, if it has one.
+    pub fn get_item_meta(&self, item: ItemID) -> Result<Option<String>> {
+        let found: Option<IVec> = self.item_meta.get(item.to_bytes())?;
+
+        match found {
+            None => Ok(None),
+            Some(vec) => {
+                let name: &str = std::str::from_utf8(&vec)
+                    .map_err(|_| snafu::NoneError)
+                    .context(InternalError)?;
+                Ok(Some(name.to_string()))
+            }
+        }
+    }
+
+    /// Locations (by `normalize_location`) referenced by more than one item's `location`
+    /// attribute, with the items referencing them. This scans every attribute in the store.
+    pub fn find_items_sharing_location(&self) -> Result<Vec<(String, Vec<ItemID>)>> {
+        let mut by_location: BTreeMap<String, Vec<ItemID>> = BTreeMap::new();
+
+        for el in self.item_attrs.iter() {
+            let (key_vec, value) = el?;
+            if key_vec.len() < ID_LEN || &key_vec[ID_LEN..] != ATTR_LOCATION.as_bytes() {
+                continue;
+            }
+
+            let item = ItemID::from(must_u8_8(&key_vec[..ID_LEN], "item_attrs")?);
+            let location = normalize_location(&String::from_utf8_lossy(&value));
+            by_location.entry(location).or_default().push(item);
+        }
+
+        Ok(by_location
+            .into_iter()
+            .filter(|(_location, items)| items.len() > 1)
+            .collect())
+    }
+
+    /// The `n` items tagged `tag` with the largest value of the numeric attribute `attr`,
+    /// largest first. Items without the attribute count as 0.
+    pub fn top_items_by_attr(
+        &self,
+        tag: TagID,
+        attr: &str,
+        n: usize,
+    ) -> Result<Vec<(ItemID, i64)>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Min-heap of the best n seen so far
+        let mut top: BinaryHeap<Reverse<(i64, ItemID)>> = BinaryHeap::with_capacity(n + 1);
+        for item_result in self.get_tag_item_ids(tag) {
+            let item = item_result?;
+            let value = self.get_item_counter(item, attr)?.unwrap_or(0);
+
+            top.push(Reverse((value, item)));
+            if top.len() > n {
+                top.pop();
+            }
+        }
+
+        // Ascending order of Reverse is descending order of value
+        Ok(top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((value, item))| (item, value))
+            .collect())
+    }
+
+    /// The `limit` tags that most often share an item with `tag`, with how many items they
+    /// share, most shared first.
+    ///
+    /// This is expensive: it scans all of `tag`'s items and joins each one to its tags, so it
+    /// costs about as much as reading every tag on every item tagged `tag`.
+    pub fn co_occurring_tags(&self, tag: TagID, limit: usize) -> Result<Vec<(Tag, u64)>> {
+        let mut counts: HashMap<TagID, u64> = HashMap::new();
+        for item_result in self.get_tag_item_ids(tag) {
+            let item = item_result?;
+            for other_result in self.get_item_tag_ids(item) {
+                let other = other_result?;
+                if other != tag {
+                    *counts.entry(other).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut counts: Vec<(TagID, u64)> = counts.into_iter().collect();
+        counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+        let mut related = Vec::new();
+        for (other, count) in counts.into_iter().take(limit) {
+            let name_vec = self
+                .tag_id_names
+                .get(other.to_bytes())?
+                .ok_or(Error::TagNotFound { id: other })?;
+            related.push((
+                tag_from_entry(&other.to_bytes(), &name_vec, "tag_id_names")?,
+                count,
+            ));
+        }
+        Ok(related)
+    }
+
+    /// Report what merging `from` into `into` would do, without changing anything.
+    pub fn merge_tags_preview(&self, from: TagID, into: TagID) -> Result<MergePreview> {
+        let mut preview = MergePreview::default();
+
+        for item_result in self.get_tag_item_ids(from) {
+            let item = item_result?;
+            if self.tag_items.contains_key(compound_key(into, item))? {
+                preview.shared += 1;
+            } else {
+                preview.moved += 1;
+            }
+        }
+
+        // Metadata on `from` that `into` doesn't already carry would be dropped. The last-used
+        // and creation times and the aliases aren't, since they're folded into the destination.
+        for el in self.tag_meta.scan_prefix(from.to_bytes()) {
+            let (key_vec, value) = el?;
+            let field = &key_vec[ID_LEN..];
+            if field == TAG_META_LAST_USED
+                || field == TAG_META_CREATED
+                || field.starts_with(TAG_META_ALIAS)
+            {
+                continue;
+            }
+
+            let into_value = self.tag_meta.get(tag_meta_key(into, field))?;
+            if into_value.as_ref() != Some(&value) {
+                preview
+                    .lost_metadata
+                    .push(String::from_utf8_lossy(field).into_owned());
+            }
+        }
+
+        Ok(preview)
+    }
+
+    /// Subscribe to changes in `item`'s tags. Each event is for one (item, tag) key in
+    /// `item_tags`: an `Event::Insert` (whose value is always empty, as only the key matters)
+    /// means the tag was applied, and an `Event::Remove` means it was taken off. Re-applying a tag
+    /// the item already has also sends an insert. Events arrive in the order the writes happened.
+    ///
+    /// Fails with `Error::ReverseIndexDisabled` if the store doesn't keep the item -> tags index.
+    pub fn watch_item(&self, item: ItemID) -> Result<sled::Subscriber> {
+        if !self.reverse_index {
+            return Err(Error::ReverseIndexDisabled {});
+        }
+        Ok(self.item_tags.watch_prefix(item.to_bytes().to_vec()))
+    }
+
+    /// Subscribe to items gaining or losing `tag`. Events are for (tag, item) keys in
+    /// `tag_items`, with the same meaning as in `watch_item`.
+    pub fn watch_tag(&self, tag: TagID) -> sled::Subscriber {
+        self.tag_items.watch_prefix(tag.to_bytes().to_vec())
+    }
+
+    pub fn get_item_tag_ids(&self, id: ItemID) -> impl Iterator<Item = Result<TagID>> + '_ {
+        self.get_item_tag_ids_owned(id)
+    }
+
+    /// Like `get_item_tag_ids`, but holds its own handle on the index instead of borrowing the
+    /// store, so it can be returned from functions or moved to another thread.
+    ///
+    /// Fails with `Error::ReverseIndexDisabled` if the store doesn't keep the item -> tags index.
+    pub fn get_item_tag_ids_owned(
+        &self,
+        id: ItemID,
+    ) -> impl Iterator<Item = Result<TagID>> + 'static {
+        if !self.reverse_index {
+            let disabled = std::iter::once(Err(Error::ReverseIndexDisabled {}));
+            return Box::new(disabled) as Box<dyn Iterator<Item = Result<TagID>>>;
+        }
+
+        let item_tags_iter = self.item_tags.scan_prefix(id.to_bytes());
+
+        let tag_ids = item_tags_iter.map(move |el| -> Result<TagID> {
+            // Get the tag key from the compound key
+            let (key_vec, _val) = el?;
+            let (_item_id, tag_id): (ItemID, TagID) =
+                from_compound_key(&must_u8_16(&key_vec, "item_tags")?);
+            Ok(tag_id)
+        });
+        Box::new(tag_ids) as Box<dyn Iterator<Item = Result<TagID>>>
+    }
+
+    pub fn get_item_tags(&self, id: ItemID) -> impl Iterator<Item = Result<Tag>> + '_ {
+        self.get_item_tags_owned(id)
+    }
+
+    /// Like `get_item_tags`, but entries that can't be read are reported on stderr and skipped
+    /// instead of ending the iteration, so one corrupt entry doesn't hide the item's other tags.
+    pub fn get_item_tags_lenient(&self, id: ItemID) -> impl Iterator<Item = Tag> + '_ {
+        self.get_item_tags(id)
+            .filter_map(move |tag_result| match tag_result {
+                Ok(tag) => Some(tag),
+                Err(e) => {
+                    eprintln!("warning: skipping a tag on item {}: {}", id, e);
+                    None
+                }
+            })
+    }
+
+    /// Like `get_item_tags`, but doesn't borrow the store. See `get_item_tag_ids_owned`.
+    pub fn get_item_tags_owned(&self, id: ItemID) -> impl Iterator<Item = Result<Tag>> + 'static {
+        let tags_iter = self.get_item_tag_ids_owned(id);
+        let tag_id_names = self.tag_id_names.clone();
+
+        tags_iter.map(move |tag_result| -> Result<Tag> {
+            let tag_id = tag_result?;
+
+            // Join to get the tag name
+            let tag_vec = tag_id_names
+                .get(tag_id.to_bytes())?
+                .ok_or(Error::InternalError {})?;
+
+            tag_from_entry(&tag_id.to_bytes(), &tag_vec, "tag_id_names")
+        })
+    }
+
+    pub fn get_tag_item_ids(&self, id: TagID) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        self.get_tag_item_ids_owned(id)
+    }
+
+    /// Items tagged with the key-value tag `key=value`. Empty if there's no such tag.
+    pub fn get_items_by_kv(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        match self.get_tag_id(&kv_tag_name(key, value)) {
+            Ok(Some(tag)) => Box::new(self.get_tag_item_ids_owned(tag))
+                as Box<dyn Iterator<Item = Result<ItemID>>>,
+            Ok(None) => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = Result<ItemID>>>,
+            Err(e) => Box::new(std::iter::once(Err(e))) as Box<dyn Iterator<Item = Result<ItemID>>>,
+        }
+    }
+
+    /// Like `get_tag_item_ids`, but doesn't borrow the store. See `get_item_tag_ids_owned`.
+    pub fn get_tag_item_ids_owned(
+        &self,
+        id: TagID,
+    ) -> impl Iterator<Item = Result<ItemID>> + 'static {
+        let tag_items_iter = self.tag_items.scan_prefix(id.to_bytes());
+
+        tag_items_iter.map(move |el| -> Result<ItemID> {
+            // Get the item key from the compound key
+            let (key_vec, _val) = el?;
+            let (_tag_id, item_id): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+
+            Ok(item_id)
+        })
+    }
+
+    /// Items tagged `id` that come after `after`, in order. This seeks straight to `after`, so
+    /// it's the cheap way to resume a listing where the last page ended.
+    pub fn get_tag_item_ids_after(
+        &self,
+        id: TagID,
+        after: ItemID,
+    ) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        let start = compound_key(id, after);
+        let end = compound_key(id, ItemID::from(u64::MAX));
+
+        self.tag_items
+            .range((Bound::Excluded(start), Bound::Included(end)))
+            .keys()
+            .map(|key_result| -> Result<ItemID> {
+                let key_vec = key_result?;
+                let (_tag_id, item_id): (TagID, ItemID) =
+                    from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+                Ok(item_id)
+            })
+    }
+
+    /// Renumber every item to a contiguous range starting at 1, keeping its tags, attributes,
+    /// metadata, and favorite flag. Returns the old -> new mapping. Items are rewritten in
+    /// chunks, each in its own transaction, so this is best run while nothing else is using the
+    /// store. Without the item -> tags index, every item's tags are read from one scan of the
+    /// tag -> items index first.
+    pub fn compact_item_ids(&self) -> Result<HashMap<ItemID, ItemID>> {
+        self.writable()?;
+        let items = self.every_item_id()?;
+
+        let mapping: HashMap<ItemID, ItemID> = items
+            .iter()
+            .enumerate()
+            .map(|(i, &old)| (old, ItemID::from(i as u64 + 1)))
+            .collect();
+
+        // An item's new ID must be free when it's moved in. Most items move down, so they're
+        // renumbered in ascending order, after whichever item held that ID has moved out. If
+        // item 0 exists, the items from 0 up to the first gap each move up by one instead, so
+        // those go first and in descending order, starting with the one moving into the gap.
+        let (moving_up, moving_down): (Vec<ItemID>, Vec<ItemID>) =
+            items.into_iter().partition(|old| mapping[old] > *old);
+        let items: Vec<ItemID> = moving_up
+            .into_iter()
+            .rev()
+            .chain(moving_down.into_iter())
+            .collect();
+
+        let mut numbers = self.numeric_keys_by_item()?;
+        let reverse_index = self.reverse_index;
+        let mut forward_tags = if reverse_index {
+            None
+        } else {
+            Some(self.tags_by_item()?)
+        };
+
+        for chunk in items.chunks(COMPACT_CHUNK) {
+            let mut moves = Vec::new();
+            for &old in chunk {
+                let new = mapping[&old];
+                if new == old {
+                    continue;
+                }
+
+                let tags = match &mut forward_tags {
+                    Some(by_item) => by_item.remove(&old).unwrap_or_default(),
+                    None => self.get_item_tag_ids(old).collect::<Result<Vec<TagID>>>()?,
+                };
+                let attrs = self
+                    .item_attrs
+                    .scan_prefix(old.to_bytes())
+                    .collect::<std::result::Result<Vec<(IVec, IVec)>, _>>()?;
+                let favorite = self.is_favorite(old)?;
+                let numeric = numbers.remove(&old).unwrap_or_default();
+                let meta = self.item_meta.get(old.to_bytes())?;
+                moves.push((old, new, tags, attrs, favorite, numeric, meta));
+            }
+
+            let trees = (
+                &self.tag_items,
+                &self.item_tags,
+                &self.item_attrs,
+                &self.favorites,
+                &self.numeric_tags,
+                &self.item_meta,
+            );
+            trees.transaction(|trees| -> ConflictableTransactionResult<()> {
+                let (tag_items, item_tags, item_attrs, favorites, numeric_tags, item_meta) = trees;
+                for (old, new, tags, attrs, favorite, numeric, meta) in &moves {
+                    let (old, new) = (*old, *new);
+
+                    for &tag in tags {
+                        tag_items.remove(&compound_key(tag, old)[..])?;
+                        tag_items.insert(&compound_key(tag, new)[..], PRESENT)?;
+                        if reverse_index {
+                            item_tags.remove(&compound_key(old, tag)[..])?;
+                            item_tags.insert(&compound_key(new, tag)[..], PRESENT)?;
+                        }
+                    }
+
+                    for (key, value) in attrs {
+                        let mut new_key = new.to_bytes().to_vec();
+                        new_key.extend_from_slice(&key[ID_LEN..]);
+                        item_attrs.remove(key)?;
+                        item_attrs.insert(new_key, value)?;
+                    }
+
+                    if *favorite {
+                        favorites.remove(&old.to_bytes()[..])?;
+                        favorites.insert(&new.to_bytes()[..], PRESENT)?;
+                    }
+
+                    for key in numeric {
+                        let mut new_key = key[..key.len() - ID_LEN].to_vec();
+                        new_key.extend_from_slice(&new.to_bytes());
+                        numeric_tags.remove(key)?;
+                        numeric_tags.insert(new_key, PRESENT)?;
+                    }
+
+                    if let Some(meta) = meta {
+                        item_meta.remove(&old.to_bytes()[..])?;
+                        item_meta.insert(&new.to_bytes()[..], meta.clone())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            for (_, new, _, attrs, _, _, _) in &moves {
+                for (key, value) in attrs {
+                    if &key[ID_LEN..] == ATTR_PERSISTENT_ID.as_bytes() {
+                        self.persistent_ids.insert(value, &new.to_bytes()[..])?;
+                    }
+                }
+            }
+        }
+
+        Ok(mapping)
+    }
+
+    /// Every item's tags, from the tag -> items index, for stores without the item -> tags one.
+    fn tags_by_item(&self) -> Result<HashMap<ItemID, Vec<TagID>>> {
+        let mut by_item: HashMap<ItemID, Vec<TagID>> = HashMap::new();
+        for key_result in self.tag_items.iter().keys() {
+            let key_vec = key_result?;
+            let (tag, item): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+            by_item.entry(item).or_default().push(tag);
+        }
+        Ok(by_item)
+    }
+
+    /// Every key in `numeric_tags`, grouped by the item it belongs to. The item is at the end of
+    /// the key, so this has to look at all of them.
+    fn numeric_keys_by_item(&self) -> Result<HashMap<ItemID, Vec<IVec>>> {
+        let mut by_item: HashMap<ItemID, Vec<IVec>> = HashMap::new();
+        for key_result in self.numeric_tags.iter().keys() {
+            let key_vec = key_result?;
+            let item = ItemID::from(must_u8_8(
+                &key_vec[key_vec.len().saturating_sub(ID_LEN)..],
+                "numeric_tags",
+            )?);
+            by_item.entry(item).or_default().push(key_vec);
+        }
+        Ok(by_item)
+    }
+
+    /// Every item mentioned anywhere in the store: tagged, carrying attributes, or favorited.
+    fn every_item_id(&self) -> Result<BTreeSet<ItemID>> {
+        let mut items = BTreeSet::new();
+
+        let keyed = [
+            ("item_tags", &self.item_tags),
+            ("item_attrs", &self.item_attrs),
+            ("item_meta", &self.item_meta),
+        ];
+        for (tree_name, tree) in &keyed {
+            for key_result in tree.iter().keys() {
+                let key_vec = key_result?;
+                let item = must_u8_8(&key_vec[..ID_LEN.min(key_vec.len())], *tree_name)?;
+                items.insert(ItemID::from(item));
+            }
+        }
+        if !self.reverse_index {
+            for el in self.tag_items.iter() {
+                let (key_vec, _val) = el?;
+                let (_tag, item): (TagID, ItemID) =
+                    from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+                items.insert(item);
+            }
+        }
+        for item_result in self.favorites() {
+            items.insert(item_result?);
+        }
+
+        Ok(items)
+    }
+
+    /// Fold over the items tagged `tag` in constant memory, stopping at the first error.
+    pub fn fold_tag_items<B, F>(&self, tag: TagID, init: B, mut f: F) -> Result<B>
+    where
+        F: FnMut(B, ItemID) -> Result<B>,
+    {
+        let mut acc = init;
+        for item_result in self.get_tag_item_ids(tag) {
+            acc = f(acc, item_result?)?;
+        }
+        Ok(acc)
+    }
+
+    pub fn set_favorite(&self, item: ItemID, favorite: bool) -> Result<()> {
+        self.writable()?;
+        if favorite {
+            self.favorites.insert(item.to_bytes(), PRESENT)?;
+        } else {
+            self.favorites.remove(item.to_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_favorite(&self, item: ItemID) -> Result<bool> {
+        Ok(self.favorites.contains_key(item.to_bytes())?)
+    }
+
+    /// All favorited items, in ID order.
+    pub fn favorites(&self) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        self.favorites.iter().map(|el| -> Result<ItemID> {
+            let (key_vec, _val) = el?;
+            Ok(ItemID::from(must_u8_8(&key_vec, "favorites")?))
+        })
+    }
+
+    /// A snapshot of the store's size and condition, for monitoring.
+    ///
+    /// The consistency check only samples the first few associations; see `verify` for a full
+    /// one. The counts aren't sampled, though: `items` collects every item ID, and sled counts
+    /// the other trees by walking them, so each call reads all of the indices. On a big store,
+    /// scrape it every few minutes rather than every few seconds.
+    pub fn health(&self) -> Result<Health> {
+        let mut inconsistencies: u64 = 0;
+        for el in self.tag_items.iter().take(HEALTH_SAMPLE) {
+            let (key_vec, _val) = el?;
+            let (tag, item): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+
+            if self.reverse_index && !self.item_tags.contains_key(compound_key(item, tag))? {
+                inconsistencies += 1;
+            }
+            if !self.tag_id_names.contains_key(tag.to_bytes())? {
+                inconsistencies += 1;
+            }
+        }
+
+        Ok(Health {
+            schema_version: SCHEMA_VERSION,
+            size_on_disk: self.sled.size_on_disk()?,
+            tags: self.tag_count() as u64,
+            items: self.every_item_id()?.len() as u64,
+            associations: self.tag_items.len() as u64,
+            inconsistencies,
+            last_modified: self.last_modified(),
+        })
+    }
+
+    /// `health`, as JSON.
+    pub fn health_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.health()?)?)
+    }
+
+    /// Check every index against the others, unlike `health`, which only samples. This reads
+    /// every association and every tag name, so it takes as long as a full export.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        for el in self.tag_items.iter().keys() {
+            let key_vec = el?;
+            let (tag, item): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+
+            if self.reverse_index && !self.item_tags.contains_key(compound_key(item, tag))? {
+                report.missing_reverse += 1;
+            }
+            if !self.tag_id_names.contains_key(tag.to_bytes())? {
+                report.unnamed_tags += 1;
+            }
+        }
+
+        if self.reverse_index {
+            for el in self.item_tags.iter().keys() {
+                let key_vec = el?;
+                let (item, tag): (ItemID, TagID) =
+                    from_compound_key(&must_u8_16(&key_vec, "item_tags")?);
+
+                if !self.tag_items.contains_key(compound_key(tag, item))? {
+                    report.missing_forward += 1;
+                }
+                if !self.tag_id_names.contains_key(tag.to_bytes())? {
+                    report.unnamed_tags += 1;
+                }
+            }
+        }
+
+        for el in self.tag_id_names.iter() {
+            let (key_vec, name_vec) = el?;
+            let id = TagID::from(must_u8_8(&key_vec, "tag_id_names")?);
+            match self.tag_name_ids.get(self.name_key(&name_vec))? {
+                Some(found) if found[..] == id.to_bytes() => {}
+                _ => report.unindexed_names += 1,
+            }
+        }
+
+        // A name maps back to its ID if it's the tag's own name, or one of its aliases
+        for el in self.tag_name_ids.iter() {
+            let (name_vec, id_vec) = el?;
+            let id = TagID::from(must_u8_8(&id_vec, "tag_name_ids")?);
+            let matches = match self.tag_id_names.get(id.to_bytes())? {
+                Some(own_name) if self.name_key(&own_name)[..] == name_vec[..] => true,
+                Some(_) => self
+                    .tag_aliases(id)?
+                    .iter()
+                    .any(|alias| self.name_key(alias.as_bytes())[..] == name_vec[..]),
+                None => false,
+            };
+            if !matches {
+                report.stale_names += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Remove the association entries `verify` counts as broken: halves of a (tag, item) pair
+    /// whose other half is missing, and entries for tags with no name. Everything is removed in
+    /// one transaction. Returns what was removed. Name index problems are only reported by
+    /// `verify`, since there's no telling which side is right.
+    pub fn repair(&self) -> Result<RepairReport> {
+        self.writable()?;
+        let report = self.repair_preview()?;
+
+        (&self.tag_items, &self.item_tags).transaction(
+            |(tag_items, item_tags)| -> ConflictableTransactionResult<()> {
+                for &(tag, item) in &report.forward {
+                    tag_items.remove(&compound_key(tag, item)[..])?;
+                }
+                for &(item, tag) in &report.reverse {
+                    item_tags.remove(&compound_key(item, tag)[..])?;
+                }
+                Ok(())
+            },
+        )?;
+
+        let touched: BTreeSet<TagID> = report
+            .forward
+            .iter()
+            .map(|&(tag, _item)| tag)
+            .chain(report.reverse.iter().map(|&(_item, tag)| tag))
+            .collect();
+        for tag in touched {
+            self.invalidate_tag_count(tag)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Report what `repair` would remove, without changing anything.
+    pub fn repair_preview(&self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+
+        for el in self.tag_items.iter().keys() {
+            let key_vec = el?;
+            let (tag, item): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+
+            let missing_reverse =
+                self.reverse_index && !self.item_tags.contains_key(compound_key(item, tag))?;
+            if missing_reverse || !self.tag_id_names.contains_key(tag.to_bytes())? {
+                report.forward.push((tag, item));
+            }
+        }
+
+        if self.reverse_index {
+            for el in self.item_tags.iter().keys() {
+                let key_vec = el?;
+                let (item, tag): (ItemID, TagID) =
+                    from_compound_key(&must_u8_16(&key_vec, "item_tags")?);
+
+                let missing_forward = !self.tag_items.contains_key(compound_key(tag, item))?;
+                if missing_forward || !self.tag_id_names.contains_key(tag.to_bytes())? {
+                    report.reverse.push((item, tag));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// When any of the store's files on disk were last written, if it has any.
+    fn last_modified(&self) -> Option<DateTime<Utc>> {
+        let entries = std::fs::read_dir(self.path.as_ref()?).ok()?;
+
+        entries
+            .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+            .max()
+            .map(DateTime::<Utc>::from)
+    }
+
+    /// Number of tags in the store.
+    pub fn tag_count(&self) -> usize {
+        self.tag_id_names.len()
+    }
+
+    /// Number of distinct items with at least one tag. With the item -> tags index this is one
+    /// ordered walk over its keys; without it, it falls back to collecting every item ID.
+    pub fn item_count(&self) -> Result<u64> {
+        if !self.reverse_index {
+            return Ok(self.every_item_id()?.len() as u64);
+        }
+
+        let mut count: u64 = 0;
+        for item_result in self.all_items() {
+            item_result?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Every item with at least one tag, once each, in order of ID. This needs the item -> tags
+    /// index, whose keys start with the item, so an item's entries are all next to each other.
+    pub fn all_items(&self) -> impl Iterator<Item = Result<ItemID>> + '_ {
+        if !self.reverse_index {
+            let disabled = std::iter::once(Err(Error::ReverseIndexDisabled {}));
+            return Box::new(disabled) as Box<dyn Iterator<Item = Result<ItemID>>>;
+        }
+
+        let mut last: Option<ItemID> = None;
+        let items =
+            self.item_tags
+                .iter()
+                .keys()
+                .filter_map(move |key_result| -> Option<Result<ItemID>> {
+                    let item = key_result
+                        .map_err(Error::from)
+                        .and_then(|key_vec| must_u8_16(&key_vec, "item_tags"))
+                        .map(|key| from_compound_key::<ItemID, TagID>(&key).0);
+
+                    match item {
+                        Ok(item) if last == Some(item) => None,
+                        Ok(item) => {
+                            last = Some(item);
+                            Some(Ok(item))
+                        }
+                        Err(e) => Some(Err(e)),
+                    }
+                });
+        Box::new(items) as Box<dyn Iterator<Item = Result<ItemID>>>
+    }
+
+    /// Number of (item, tag) associations in the store.
+    pub fn association_count(&self) -> usize {
+        self.tag_items.len()
+    }
+
+    /// Size of the store's files on disk, in bytes.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.sled.size_on_disk()?)
+    }
+
+    /// Append a record to the persistent import log.
+    pub fn record_import(&self, record: &ImportRecord) -> Result<()> {
+        self.writable()?;
+        let seq = self.id()?;
+        self.import_log
+            .insert(&seq.to_be_bytes(), serde_json::to_vec(record)?)?;
+        Ok(())
+    }
+
+    /// Every import recorded in this store, oldest first.
+    pub fn import_history(&self) -> impl Iterator<Item = Result<ImportRecord>> + '_ {
+        self.import_log.iter().map(|el| -> Result<ImportRecord> {
+            let (_key, record_vec) = el?;
+            Ok(serde_json::from_slice(&record_vec)?)
+        })
+    }
+
+    /// Every tag in the store, in ID order.
+    pub fn all_tags(&self) -> impl Iterator<Item = Result<Tag>> + '_ {
+        self.tag_id_names.iter().map(|el| -> Result<Tag> {
+            let (key_vec, name_vec) = el?;
+            tag_from_entry(&key_vec, &name_vec, "tag_id_names")
+        })
+    }
+
+    /// Write every tagged item to `out`, one JSON object per line:
+    /// `{"item": 7, "name": "Cello Suite No. 1", "tags": ["Bach", "Cello"]}`. `name` is the item's
+    /// metadata, or null. Items are read and written one at a time, so this doesn't hold the
+    /// store in memory. Items without tags are left out. This needs the item -> tags index.
+    pub fn export_ndjson<W: Write>(&self, mut out: W) -> Result<()> {
+        for item_result in self.all_items() {
+            let item = item_result?;
+            let tags = self
+                .get_item_tags(item)
+                .map(|tag| tag.map(|tag| tag.name))
+                .collect::<Result<Vec<String>>>()?;
+            let line = serde_json::json!({
+                "item": item,
+                "name": self.get_item_meta(item)?,
+                "tags": tags,
+            });
+
+            serde_json::to_writer(&mut out, &line)?;
+            out.write_all(b"\n")?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Write the tag -> items index to `out`, one line per tag with its items in ID order:
+    /// `Cello\t7,12,40`. Tags without items are left out. Lines are written as `tag_items` is
+    /// scanned, so even a huge tag isn't held in memory. Fails with `Error::TagNotFound` if an
+    /// entry points at a tag with no name.
+    pub fn export_tag_index<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut current: Option<TagID> = None;
+        for el in self.tag_items.iter().keys() {
+            let key_vec = el?;
+            let (tag, item): (TagID, ItemID) =
+                from_compound_key(&must_u8_16(&key_vec, "tag_items")?);
+
+            if current == Some(tag) {
+                write!(out, ",{}", item)?;
+                continue;
+            }
+
+            if current.is_some() {
+                out.write_all(b"\n")?;
+            }
+            let name_vec = self
+                .tag_id_names
+                .get(&tag.to_bytes())?
+                .ok_or(Error::TagNotFound { id: tag })?;
+            let name = tag_from_entry(&tag.to_bytes(), &name_vec, "tag_id_names")?.name;
+            write!(out, "{}\t{}", name, item)?;
+            current = Some(tag);
+        }
+        if current.is_some() {
+            out.write_all(b"\n")?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Read items written by `export_ndjson`, giving each one a fresh ID. Returns how many items
+    /// were imported. The exported `item` ID is ignored, and blank lines are skipped.
+    pub fn import_ndjson<R: BufRead>(&self, input: R) -> Result<usize> {
+        let mut imported: usize = 0;
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ExportedItem =
+                serde_json::from_str(&line).context(BadRecord { line: i + 1 })?;
+
+            let item = ItemID::from(self.id()?);
+            if let Some(name) = &record.name {
+                self.set_item_meta(item, name)?;
+            }
+            for tag_name in &record.tags {
+                self.tag_string(item, tag_name)?;
+            }
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// Every (item, tag) association in the store, ordered by item and then tag. This needs the
+    /// item -> tags index.
+    pub fn all_associations(&self) -> impl Iterator<Item = Result<(ItemID, TagID)>> + '_ {
+        if !self.reverse_index {
+            let disabled = std::iter::once(Err(Error::ReverseIndexDisabled {}));
+            return Box::new(disabled) as Box<dyn Iterator<Item = Result<(ItemID, TagID)>>>;
+        }
+
+        let associations = self.item_tags.iter().map(|el| -> Result<(ItemID, TagID)> {
+            let (key_vec, _val) = el?;
+            Ok(from_compound_key(&must_u8_16(&key_vec, "item_tags")?))
+        });
+        Box::new(associations) as Box<dyn Iterator<Item = Result<(ItemID, TagID)>>>
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+
+        // Mark the store as cleanly closed. If this fails, the next open just warns.
+        if self.meta.remove(META_OPEN).is_ok() {
+            let _ = self.sled.flush();
+        }
+    }
+}
+
+/// The key for the pair (a, b): both IDs' bytes, `a` first. Every two-ID key in the store is
+/// built here and taken apart by `from_compound_key`.
+fn compound_key<T1: Id, T2: Id>(a: T1, b: T2) -> [u8; COMPOUND_LEN] {
+    let a_bytes: [u8; ID_LEN] = a.into();
+    let b_bytes: [u8; ID_LEN] = b.into();
+    let mut dest = [0u8; COMPOUND_LEN];
+
+    dest[..ID_LEN].copy_from_slice(&a_bytes);
+    dest[ID_LEN..].copy_from_slice(&b_bytes);
+    dest
+}
+
+fn from_compound_key<T1: Id, T2: Id>(compound: &[u8; COMPOUND_LEN]) -> (T1, T2) {
+    let mut a_bytes = [0u8; ID_LEN];
+    let mut b_bytes = [0u8; ID_LEN];
+
+    a_bytes.copy_from_slice(&compound[..ID_LEN]);
+    b_bytes.copy_from_slice(&compound[ID_LEN..]);
+    (T1::from(a_bytes), T2::from(b_bytes))
+}
+
+/// Write one chunk of a backup: a big-endian u32 length and that many bytes. `None` is written
+/// as `END_OF_LIST` alone, and marks the end of a list of chunks.
+fn write_chunk<W: Write>(out: &mut W, bytes: Option<&[u8]>) -> Result<()> {
+    match bytes {
+        Some(bytes) => {
+            // Lengths from END_OF_LIST up can't be told apart from the marker
+            let len = u32::try_from(bytes.len())
+                .ok()
+                .filter(|&len| len < END_OF_LIST)
+                .ok_or(Error::ChunkTooLarge { len: bytes.len() })?;
+            out.write_all(&len.to_be_bytes())?;
+            out.write_all(bytes)?;
+        }
+        None => out.write_all(&END_OF_LIST.to_be_bytes())?,
+    }
+    Ok(())
+}
+
+/// Read a chunk written by `write_chunk`.
+fn read_chunk<R: Read>(input: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    input
+        .read_exact(&mut len)
+        .map_err(|_| Error::BadArchive {})?;
+    let len = u32::from_be_bytes(len);
+    if len == END_OF_LIST {
+        return Ok(None);
+    }
+    if len > END_OF_LIST {
+        return Err(Error::BadArchive {});
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    input
+        .read_exact(&mut bytes)
+        .map_err(|_| Error::BadArchive {})?;
+    Ok(Some(bytes))
+}
+
+/// Normalize a file location so that different spellings of the same file compare equal:
+/// `file://` URLs (with or without `localhost`) become plain percent-decoded paths.
+pub fn normalize_location(location: &str) -> String {
+    let path = if let Some(rest) = strip_prefix(location, "file://localhost") {
+        rest
+    } else if let Some(rest) = strip_prefix(location, "file://") {
+        rest
+    } else {
+        location
+    };
+
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+fn strip_prefix<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    if text.starts_with(prefix) {
+        Some(&text[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur: Vec<usize> = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitute = prev[j] + if a_char == b_char { 0 } else { 1 };
+            cur[j + 1] = std::cmp::min(substitute, std::cmp::min(prev[j + 1], cur[j]) + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+/// The `tag_meta` field recording `alias` as an alias of its tag.
+fn alias_field(alias: &str) -> Vec<u8> {
+    let mut field = TAG_META_ALIAS.to_vec();
+    field.extend_from_slice(alias.as_bytes());
+    field
+}
+
+fn tag_meta_key(tag: TagID, field: &[u8]) -> Vec<u8> {
+    let mut key = tag.to_bytes().to_vec();
+    key.extend_from_slice(field);
+    key
+}
+
+/// Key for the numeric tag `value` under `namespace` on `item`. Namespaces can't contain NUL.
+fn numeric_key(namespace: &str, value: i64, item: ItemID) -> Vec<u8> {
+    let mut key = namespace.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&encode_number(value));
+    key.extend_from_slice(&item.to_bytes());
+    key
+}
+
+/// Encode a number so that byte order matches numeric order: big-endian, with the sign bit
+/// flipped.
+fn encode_number(value: i64) -> [u8; 8] {
+    ((value as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn decode_number(bytes: [u8; 8]) -> i64 {
+    (u64::from_be_bytes(bytes) ^ (1 << 63)) as i64
+}
+
+fn item_attr_key(item: ItemID, key: &str) -> Vec<u8> {
+    let mut attr_key = item.to_bytes().to_vec();
+    attr_key.extend_from_slice(key.as_bytes());
+    attr_key
+}
+
+/// The stored name of the key-value tag `key=value`.
+fn kv_tag_name(key: &str, value: &str) -> String {
+    format!("{}{}{}", key, KV_SEPARATOR, value)
+}
+
+/// Decode a `tag_id_names` entry into a `Tag`.
+fn tag_from_entry(key: &[u8], name: &[u8], tree: &'static str) -> Result<Tag> {
+    let id = TagID::from(must_u8_8(key, tree)?);
+    let name: &str = std::str::from_utf8(name).map_err(|_| Error::InvalidTagName {
+        id,
+        bytes: name.to_vec(),
+    })?;
+
+    Ok(Tag::new(id, name.to_string()))
+}
+
+/// Read a compound key (or value) from `tree`, failing with `Error::CorruptKey` if it's the
+/// wrong length.
+fn must_u8_16(slice: &[u8], tree: &'static str) -> Result<[u8; COMPOUND_LEN]> {
+    slice.try_into().map_err(|_| Error::CorruptKey {
+        len: slice.len(),
+        expected: COMPOUND_LEN,
+        tree,
+    })
+}
+
+/// Read an ID (or another 8-byte value) from `tree`, like `must_u8_16`.
+fn must_u8_8(slice: &[u8], tree: &'static str) -> Result<[u8; ID_LEN]> {
+    slice.try_into().map_err(|_| Error::CorruptKey {
+        len: slice.len(),
+        expected: ID_LEN,
+        tree,
+    })
+}
+
+generate_id!(TagID);
+generate_id!(ItemID);
+
+/// Store health, as reported by `Store::health`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub schema_version: u32,
+    pub size_on_disk: u64,
+    pub tags: u64,
+    pub items: u64,
+    pub associations: u64,
+
+    /// Index discrepancies found in a sample of the associations
+    pub inconsistencies: u64,
+
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Index discrepancies found by `Store::verify`, by kind.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// `tag_items` entries with no matching `item_tags` entry
+    pub missing_reverse: u64,
+
+    /// `item_tags` entries with no matching `tag_items` entry
+    pub missing_forward: u64,
+
+    /// Entries in either index whose tag has no name
+    pub unnamed_tags: u64,
+
+    /// Tags whose name doesn't look up to their ID
+    pub unindexed_names: u64,
+
+    /// Names that look up to a tag that's gone, or that no longer has that name or alias
+    pub stale_names: u64,
+}
+
+impl VerifyReport {
+    /// Whether every check passed.
+    pub fn is_consistent(&self) -> bool {
+        *self == VerifyReport::default()
+    }
+}
+
+/// Association entries removed by `Store::repair`, or that would be by `repair_preview`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// (tag, item) entries in `tag_items`
+    pub forward: Vec<(TagID, ItemID)>,
+
+    /// (item, tag) entries in `item_tags`
+    pub reverse: Vec<(ItemID, TagID)>,
+}
+
+/// One line of `export_ndjson` output, as read back by `import_ndjson`.
+#[derive(Deserialize)]
+struct ExportedItem {
+    #[serde(default)]
+    name: Option<String>,
+
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// An entry in the import log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportRecord {
+    pub time: DateTime<Utc>,
+
+    /// Persistent ID of the library that was imported
+    pub library_id: String,
+
+    pub items: u64,
+    pub tags_created: u64,
+}
+
+/// The effect a tag merge would have.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MergePreview {
+    /// Items that would be re-pointed to the destination tag
+    pub moved: u64,
+
+    /// Items already carrying both tags, which would be deduplicated
+    pub shared: u64,
+
+    /// Metadata fields on the source tag that would be lost
+    pub lost_metadata: Vec<String>,
+}
+
+/// Separates the key from the value in the stored name of a key-value tag.
+pub const KV_SEPARATOR: char = '\u{0}';
+
+/// Separates the levels of a hierarchical tag name, like `genre/classical/baroque`.
+pub const TAG_PATH_SEPARATOR: char = '/';
+
+/// Serialized as just `{"id": 42, "name": "Bach"}`; the key and value are split out of the name
+/// again when it's deserialized.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "TagRecord")]
+pub struct Tag {
+    pub id: TagID,
+
+    /// The name as stored. For key-value tags this is `key`, `KV_SEPARATOR`, `value`.
+    pub name: String,
+
+    /// For key-value tags, the two halves of the name
+    #[serde(skip_serializing)]
+    pub key: Option<String>,
+    #[serde(skip_serializing)]
+    pub value: Option<String>,
+}
+
+// The serialized form of a `Tag`
+#[derive(Deserialize)]
+struct TagRecord {
+    id: TagID,
+    name: String,
+}
+
+impl From<TagRecord> for Tag {
+    fn from(record: TagRecord) -> Tag {
+        Tag::new(record.id, record.name)
+    }
+}
+
+impl Tag {
+    /// Build a tag from its stored name, splitting out the key and value if it has them.
+    pub fn new(id: TagID, name: String) -> Tag {
+        let (key, value) = match name.find(KV_SEPARATOR) {
+            Some(i) => (
+                Some(name[..i].to_string()),
+                Some(name[i + KV_SEPARATOR.len_utf8()..].to_string()),
+            ),
+            None => (None, None),
+        };
+
+        Tag {
+            id,
+            name,
+            key,
+            value,
+        }
+    }
 }
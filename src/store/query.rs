@@ -0,0 +1,273 @@
+//! Set operations over tag posting lists.
+//!
+//! Each tag's items come out of `tag_items` in ascending `ItemID` order, since the keys are
+//! big-endian. Everything here relies on that to combine lists by merging, without ever
+//! collecting a whole list into memory.
+
+use super::{ItemID, Result, Store, TagID};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::iter::Peekable;
+
+type Items = Box<dyn Iterator<Item = Result<ItemID>>>;
+
+impl Store {
+    /// Items tagged with every one of `tags`. No tags means no items.
+    pub fn get_items_with_all_tags(&self, tags: &[TagID]) -> impl Iterator<Item = Result<ItemID>> {
+        match tags {
+            [] => Box::new(std::iter::empty()) as Items,
+            [tag] => Box::new(self.get_tag_item_ids_owned(*tag)),
+            _ => Box::new(Intersection::new(self.posting_lists(tags))),
+        }
+    }
+
+    /// Items tagged with at least one of `tags`, each once. Tags with no items contribute
+    /// nothing.
+    pub fn get_items_with_any_tag(&self, tags: &[TagID]) -> impl Iterator<Item = Result<ItemID>> {
+        match tags {
+            [] => Box::new(std::iter::empty()) as Items,
+            [tag] => Box::new(self.get_tag_item_ids_owned(*tag)),
+            _ => Box::new(Union::new(self.posting_lists(tags))),
+        }
+    }
+
+    /// Items tagged `include` but none of `exclude`.
+    pub fn get_items_excluding(
+        &self,
+        include: TagID,
+        exclude: &[TagID],
+    ) -> impl Iterator<Item = Result<ItemID>> {
+        Difference::new(
+            Box::new(self.get_tag_item_ids_owned(include)) as Items,
+            self.posting_lists(exclude),
+        )
+    }
+
+    /// Items with all of `all` and at least one of `any`, minus those with any of `none`. An
+    /// empty `all` or `any` doesn't constrain the result, but at least one must be given.
+    pub fn query(
+        &self,
+        all: &[TagID],
+        any: &[TagID],
+        none: &[TagID],
+    ) -> impl Iterator<Item = Result<ItemID>> {
+        let mut required = self.posting_lists(all);
+        if !any.is_empty() {
+            required.push(Box::new(self.get_items_with_any_tag(any)));
+        }
+
+        let matched: Items = match required.len() {
+            0 => Box::new(std::iter::empty()),
+            1 => required.pop().expect("one list"),
+            _ => Box::new(Intersection::new(required)),
+        };
+
+        if none.is_empty() {
+            matched
+        } else {
+            Box::new(Difference::new(matched, self.posting_lists(none)))
+        }
+    }
+
+    fn posting_lists(&self, tags: &[TagID]) -> Vec<Items> {
+        tags.iter()
+            .map(|&tag| Box::new(self.get_tag_item_ids_owned(tag)) as Items)
+            .collect()
+    }
+}
+
+/// Items present in every one of several sorted streams.
+struct Intersection<I: Iterator<Item = Result<ItemID>>> {
+    cursors: Vec<Peekable<I>>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Intersection<I> {
+    fn new(streams: Vec<I>) -> Intersection<I> {
+        Intersection {
+            done: streams.is_empty(),
+            cursors: streams.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Iterator for Intersection<I> {
+    type Item = Result<ItemID>;
+
+    fn next(&mut self) -> Option<Result<ItemID>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            // Find the smallest and largest IDs at the heads of the cursors
+            let mut smallest: Option<(usize, ItemID)> = None;
+            let mut largest: Option<ItemID> = None;
+            for (i, cursor) in self.cursors.iter_mut().enumerate() {
+                let head = match cursor.peek() {
+                    Some(Ok(head)) => *head,
+                    Some(Err(_)) => {
+                        self.done = true;
+                        return cursor.next();
+                    }
+                    None => {
+                        // Once any list runs out, nothing else can be in all of them
+                        self.done = true;
+                        return None;
+                    }
+                };
+
+                if smallest.map_or(true, |(_, id)| head < id) {
+                    smallest = Some((i, head));
+                }
+                largest = largest.max(Some(head));
+            }
+
+            let (smallest_cursor, smallest_id) = smallest?;
+            if Some(smallest_id) == largest {
+                // Every cursor is on the same item
+                for cursor in &mut self.cursors {
+                    cursor.next();
+                }
+                return Some(Ok(smallest_id));
+            }
+
+            self.cursors[smallest_cursor].next();
+        }
+    }
+}
+
+/// Items present in any of several sorted streams, deduplicated.
+struct Union<I: Iterator<Item = Result<ItemID>>> {
+    cursors: Vec<I>,
+
+    // The head of each cursor that hasn't run out, by cursor index. Filled on the first `next`.
+    heads: BinaryHeap<Reverse<(ItemID, usize)>>,
+    started: bool,
+
+    last: Option<ItemID>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Union<I> {
+    fn new(streams: Vec<I>) -> Union<I> {
+        Union {
+            heads: BinaryHeap::with_capacity(streams.len()),
+            cursors: streams,
+            started: false,
+            last: None,
+            done: false,
+        }
+    }
+
+    /// Pull the next item from a cursor into the heap. Errors end the union.
+    fn advance(&mut self, cursor: usize) -> Option<Result<ItemID>> {
+        match self.cursors[cursor].next() {
+            Some(Ok(id)) => {
+                self.heads.push(Reverse((id, cursor)));
+                None
+            }
+            Some(Err(e)) => {
+                self.done = true;
+                Some(Err(e))
+            }
+            None => None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Iterator for Union<I> {
+    type Item = Result<ItemID>;
+
+    fn next(&mut self) -> Option<Result<ItemID>> {
+        if self.done {
+            return None;
+        }
+
+        if !self.started {
+            self.started = true;
+            for cursor in 0..self.cursors.len() {
+                if let Some(err) = self.advance(cursor) {
+                    return Some(err);
+                }
+            }
+        }
+
+        loop {
+            let Reverse((id, cursor)) = self.heads.pop()?;
+            if let Some(err) = self.advance(cursor) {
+                return Some(err);
+            }
+
+            // Skip the same item coming from another list
+            if self.last == Some(id) {
+                continue;
+            }
+            self.last = Some(id);
+            return Some(Ok(id));
+        }
+    }
+}
+
+/// Items in one sorted stream that aren't in any of several others.
+struct Difference<I: Iterator<Item = Result<ItemID>>> {
+    include: I,
+    excludes: Vec<Peekable<I>>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Difference<I> {
+    fn new(include: I, excludes: Vec<I>) -> Difference<I> {
+        Difference {
+            include,
+            excludes: excludes.into_iter().map(Iterator::peekable).collect(),
+            done: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<ItemID>>> Iterator for Difference<I> {
+    type Item = Result<ItemID>;
+
+    fn next(&mut self) -> Option<Result<ItemID>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let id = match self.include.next()? {
+                Ok(id) => id,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+            // Bring every exclude cursor up to `id`, and see if any of them landed on it
+            let mut excluded = false;
+            for cursor in &mut self.excludes {
+                loop {
+                    let head = match cursor.peek() {
+                        Some(Ok(head)) => *head,
+                        Some(Err(_)) => {
+                            self.done = true;
+                            return cursor.next();
+                        }
+                        None => break,
+                    };
+
+                    if head < id {
+                        cursor.next();
+                    } else {
+                        excluded |= head == id;
+                        break;
+                    }
+                }
+            }
+
+            if !excluded {
+                return Some(Ok(id));
+            }
+        }
+    }
+}